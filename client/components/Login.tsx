@@ -3,6 +3,7 @@ import { GoogleLogin } from '@react-oauth/google'
 import { AppContext, AppContextType } from './appctx'
 import { FC, useContext, useState, useEffect } from 'react'
 import { toastError } from './toast'
+import { getApiResult } from './api'
 import { ClipLoader } from 'react-spinners'
 
 const sleep = (ms: number) => new Promise(resolve => setTimeout(resolve, ms))
@@ -40,11 +41,17 @@ const Login: FC<{}> = () => {
               <div className="level-item">
               <GoogleLogin
                 size='large'
-                onSuccess={resp => {
+                onSuccess={async resp => {
                   if (resp?.credential) {
                     try {
                       Auth.setToken(resp.credential ? resp.credential : '')
                       Auth.setEmail(decodeEmail(resp.credential))
+                      // register with the invite code or link this page was opened with
+                      const invite = new URLSearchParams(window.location.search).get('invite')
+                      if (invite) {
+                        await getApiResult('/api/v1/user/register', 'post', { invite_code: invite }, undefined, 'cannot use invite')
+                        window.history.replaceState(null, '', window.location.pathname)
+                      }
                       setLoading(true)
                       setLoggedIn(true)
                     } catch (e) {
@@ -30,7 +30,7 @@ const MessageList: FC<ContainerProps> = () => {
       let messages: IMessage[] = []
       const successMessage = promptAfter ? 'message list refreshed successfully' : undefined
       setLoading(true)
-      const { success, data } = await getApiResult('/message-list', 'get', {}, successMessage, 'failed to get messages', () => setLoggedIn(false))
+      const { success, data } = await getApiResult('/api/v1/messages', 'get', {}, successMessage, 'failed to get messages', () => setLoggedIn(false))
       if (success && data) {
         messages = data as unknown as IMessage[]
       }
@@ -48,9 +48,22 @@ const MessageList: FC<ContainerProps> = () => {
     f()
   }, [listMessages, setLoading, shouldRefresh])
 
+  const messageDetails = (k: IMessage) => (
+    <>
+      {k.title ? <p className="has-text-weight-semibold mb-1">{k.title}</p> : <></>}
+      {k.labels && k.labels.length > 0
+        ? <p className="mb-2">{k.labels.map((l) => <span key={l} className="tag is-info is-light is-rounded mr-1">{l}</span>)}</p>
+        : <></>}
+    </>
+  )
+
   const deleteMessage = async (id: string): Promise<any> => {
-    const payload = { message_id: id }
-    const result = await getApiResult('/message', 'delete', payload, 'Message deleted successfully', 'error deleting message', () => setLoggedIn(false))
+    const result = await getApiResult(`/api/v1/messages/${encodeURIComponent(id)}`, 'delete', {}, 'Message deleted successfully', 'error deleting message', () => setLoggedIn(false))
+    if (result.success) toggleRefresh()
+  }
+
+  const acceptMessage = async (id: string): Promise<any> => {
+    const result = await getApiResult(`/api/v1/messages/${encodeURIComponent(id)}/accept`, 'post', {}, 'Message accepted', 'error accepting message', () => setLoggedIn(false))
     if (result.success) toggleRefresh()
   }
 
@@ -92,7 +105,7 @@ const MessageList: FC<ContainerProps> = () => {
   }
 
   const sendPingNotification = async (target: string) => {
-    await getApiResult('/test-notification', 'post', { recipient: target }, 'request sent!', `fail to send notification request for ${target}`, () => setLoggedIn(false))
+    await getApiResult('/api/v1/notifications/test', 'post', { recipient: target }, 'request sent!', `fail to send notification request for ${target}`, () => setLoggedIn(false))
   }
 
   const confirmPingNotification = (recipient: string) => {
@@ -163,8 +176,15 @@ const MessageList: FC<ContainerProps> = () => {
                       </header>
                       <div className="card-content">
                         <div className="content">
+                          {messageDetails(k)}
                           <p><span className="tag is-warning is-light is-rounded">outbound</span>
-                          {k.revealed ? <span className="tag is-link is-light is-rounded">revealed</span> : <></>}</p>
+                          {k.revealed ? <span className="tag is-link is-light is-rounded">revealed</span> : <></>}
+                          {k.pending_invite ? <span className="tag is-warning is-light is-rounded">invite pending</span> : <></>}
+                          {k.require_acceptance
+                            ? (k.accepted_on
+                                ? <span className="tag is-success is-light is-rounded">accepted {dayjs.unix(k.accepted_on).fromNow()}</span>
+                                : <span className="tag is-warning is-light is-rounded">awaiting acceptance</span>)
+                            : <></>}</p>
                           {k.revealed
                             ? <>This message has been revealed to the recipient!</>
                             : <>
@@ -174,6 +194,7 @@ const MessageList: FC<ContainerProps> = () => {
                             The recipient was last seen {dayjs.unix(k.recipient_last_seen).fromNow()}. Tap on their email to send a ping notification message.
                           </>
                           }
+                          {k.note ? <p className="mt-3"><em>Note for the recipient:</em> {k.note}</p> : <></>}
                           <br />
                         </div>
                       </div>
@@ -195,9 +216,11 @@ const MessageList: FC<ContainerProps> = () => {
                       </header>
                       <div className="card-content">
                         <div className="content">
+                          {messageDetails(k)}
                           <p>
                           <span className="tag is-warning is-light is-rounded">inbound</span>
-                          {k.system_share ? <span className="tag is-primary is-light is-rounded">unlocked</span> : <></>}</p>
+                          {k.system_share ? <span className="tag is-primary is-light is-rounded">unlocked</span> : <></>}
+                          {k.require_acceptance && !k.accepted_on ? <span className="tag is-warning is-light is-rounded">awaiting your acceptance</span> : <></>}</p>
                           {k.system_share
                             ? <>You can reveal this message now!</>
                             : <>You can reveal this message {dayjs.duration(k.verify_every_minutes * k.max_failed_verification, 'minute').humanize()} after
@@ -207,10 +230,14 @@ const MessageList: FC<ContainerProps> = () => {
 
                             </>
                           }
+                          {k.note ? <p className="mt-3"><em>Note from the owner:</em> {k.note}</p> : <></>}
                           <br />
                         </div>
                       </div>
                       <footer className="card-footer">
+                        {k.require_acceptance && !k.accepted_on
+                          ? <a onClick={() => acceptMessage(k.id)} className="card-footer-item has-text-success-dark">Accept</a>
+                          : <></>}
                         <a onClick={() => { k.system_share ? displayDecryption(k.owner, k.recipient, k.system_share) : toastError('you can\'t reveal this message yet!') }} className="card-footer-item has-text-primary-dark">Reveal Content</a>
                         <a onClick={() => { k.system_share ? confirmDeleteMessage(k.id) : toastError('you can\'t delete this message yet!') }} className="card-footer-item has-text-danger-dark">Delete Message</a>
                       </footer>
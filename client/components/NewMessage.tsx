@@ -19,6 +19,9 @@ const NewMessage: FC<ContainerProps> = () => {
   const [verifyTime, setVerifyTime] = useState(1)
   const [verifyLimit, setVerifyLimit] = useState(1)
   const [email, setEmail] = useState('')
+  const [title, setTitle] = useState('')
+  const [labels, setLabels] = useState('')
+  const [note, setNote] = useState('')
 
   const [thresholdTime, setThresholdTime] = useState('1')
   const [thresholdTimeUnit, setThresholdTimeUnit] = useState('day(s)')
@@ -89,10 +92,13 @@ const NewMessage: FC<ContainerProps> = () => {
         verify_every_minutes: verifyEveryMinutes,
         max_failed_verification: maxFailedVerification,
         recipient,
-        system_share: systemShare
+        system_share: systemShare,
+        title: title.trim(),
+        labels: labels.split(',').map(l => l.trim()).filter(l => l !== ''),
+        note: note.trim()
       }
     }
-    const result = await getApiResult('/message', 'post', payload, 'Message registered successfully', 'error registering message', () => setLoggedIn(false))
+    const result = await getApiResult('/api/v1/messages', 'post', payload, 'Message registered successfully', 'error registering message', () => setLoggedIn(false))
     if (result.success) {
       toggleRefresh()
     }
@@ -125,6 +131,18 @@ const NewMessage: FC<ContainerProps> = () => {
             <label >Recipient google ID (email)</label>
             <input className='input' type='email' id='email' placeholder='enter the recipient email' value={email} onChange={ev => setEmail(ev.target.value)} required />
           </div>
+          <div>
+            <label >Title (optional)</label>
+            <input className='input' type='text' id='title' placeholder='to tell your messages apart' maxLength={100} value={title} onChange={ev => setTitle(ev.target.value)} />
+          </div>
+          <div>
+            <label >Labels (optional, comma separated)</label>
+            <input className='input' type='text' id='labels' placeholder='e.g. family, finance' value={labels} onChange={ev => setLabels(ev.target.value)} />
+          </div>
+          <div>
+            <label >Note for the recipient (optional, shown once revealed)</label>
+            <TextareaAutosize className="textarea" id='note' placeholder='not encrypted, keep secrets in the message' maxLength={1000} value={note} onChange={(ev) => setNote(ev.target.value)} />
+          </div>
           <div>
             <label >Secret message</label>
             <TextareaAutosize required className="textarea" id='secret-message' placeholder='your secret message' auto-grow='true' value={secretMessage} onChange={(ev) => splitShare(ev)} />
@@ -10,7 +10,7 @@ export default function Owner () {
 
   const ownerPong = useCallback(
     async (): Promise<boolean> => {
-      const result = await getApiResult('/user-pong', 'get', {})
+      const result = await getApiResult('/api/v1/user/check-in', 'post', {})
       return result.success
     }, [])
 
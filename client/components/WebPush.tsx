@@ -26,6 +26,69 @@ const WebPush: FC<ContainerProps> = () => {
     return outputArray
   }
 
+  const pushSubscribe = (reg: ServiceWorkerRegistration, pubKey: string) =>
+    reg.pushManager.subscribe({
+      userVisibleOnly: true,
+      applicationServerKey: base64ToUint8Array(pubKey)
+    })
+
+  const saveSubscription = async (sub: PushSubscription, pubKey: string, successMessage?: string) => {
+    const result = await getApiResult('/api/v1/user/subscription', 'put', { subscription: sub, push_pubkey: pubKey }, successMessage, 'failed to enable push notification', () => setLoggedIn(false))
+    if (result.success) {
+      setSubscription(sub)
+      setIsSubscribed(true)
+    }
+  }
+
+  const subscribe = async (reg: ServiceWorkerRegistration, successMessage?: string) => {
+    const pubKey = config.getPushPubkey()
+    if (!pubKey) return
+    let sub: PushSubscription
+    try {
+      sub = await pushSubscribe(reg, pubKey)
+    } catch (e) {
+      toastError(`browser failed to subscribe for push notification: ${e}`)
+      return
+    }
+    await saveSubscription(sub, pubKey, successMessage)
+  }
+
+  const replaceSubscription = async (reg: ServiceWorkerRegistration, sub: PushSubscription, pubKey: string) => {
+    try {
+      return await pushSubscribe(reg, pubKey)
+    } catch (e) {
+      // browsers refuse a new key while the old subscription exists, so it
+      // only goes once there's no other way
+      if (!(e instanceof DOMException && e.name === 'InvalidStateError')) throw e
+      await sub.unsubscribe()
+      setSubscription(undefined)
+      setIsSubscribed(false)
+      return await pushSubscribe(reg, pubKey)
+    }
+  }
+
+  // subscriptions created before the server rotated its push key are replaced
+  // with ones for the current key, retrying a few times if that fails
+  const renewIfNeeded = async (reg: ServiceWorkerRegistration, sub: PushSubscription, attempt = 1) => {
+    const pubKey = config.getPushPubkey()
+    if (!pubKey) return
+    const { success, data } = await getApiResult('/api/v1/user/subscription', 'get', {})
+    if (!success || !(data as any).resubscribe) return
+    let renewed: PushSubscription
+    try {
+      renewed = await replaceSubscription(reg, sub, pubKey)
+    } catch (e) {
+      if (attempt < 3) {
+        toastError(`failed to renew push notification, retrying: ${e}`)
+        setTimeout(() => renewIfNeeded(reg, sub, attempt + 1), attempt * 30 * 1000)
+      } else {
+        toastError(`failed to renew push notification, please enable it again: ${e}`)
+      }
+      return
+    }
+    await saveSubscription(renewed, pubKey, 'push notification renewed')
+  }
+
   useEffect(() => {
     if (typeof window !== 'undefined' && 'serviceWorker' in navigator && 'workbox' in window) {
       // run only in browser
@@ -34,6 +97,7 @@ const WebPush: FC<ContainerProps> = () => {
           if (sub && !(sub.expirationTime && Date.now() > sub.expirationTime - 5 * 60 * 1000)) {
             setSubscription(sub)
             setIsSubscribed(true)
+            renewIfNeeded(reg, sub)
           }
         })
         setRegistration(reg)
@@ -43,21 +107,8 @@ const WebPush: FC<ContainerProps> = () => {
 
   const subscribeButtonOnClick = async (event: any) => {
     event.preventDefault()
-    const pubKey = config.getPushPubkey()
-    if (!registration || !pubKey) return
-    const sub = await registration.pushManager.subscribe({
-      userVisibleOnly: true,
-      applicationServerKey: base64ToUint8Array(pubKey)
-    })
-    if (!sub) {
-      toastError('browser failed to subscribe for push notification')
-      return
-    }
-    const result = await getApiResult('/subscribe-user', 'post', { subscription: sub }, 'push notification enabled successfully', 'failed to enable push notification', () => setLoggedIn(false))
-    if (result.success) {
-      setSubscription(sub)
-      setIsSubscribed(true)
-    }
+    if (!registration) return
+    await subscribe(registration, 'push notification enabled successfully')
   }
 
   const unsubscribeButtonOnClick = async (event: any) => {
@@ -72,11 +123,11 @@ const WebPush: FC<ContainerProps> = () => {
     setIsSubscribed(false)
     toastSuccess('push notification disabled successfully')
     // API result doesn't matter, user will no longer receive notifications after the above
-    await getApiResult('/unsubscribe-user', 'post', {})
+    await getApiResult('/api/v1/user/subscription', 'delete', {})
   }
 
   const testNotification = async () => {
-    await getApiResult('/test-notification', 'post', {}, 'request sent! check the server logs if you dont receive a message soon', 'fail to request for test notification', () => setLoggedIn(false))
+    await getApiResult('/api/v1/notifications/test', 'post', {}, 'request sent! check the server logs if you dont receive a message soon', 'fail to request for test notification', () => setLoggedIn(false))
   }
 
   return (
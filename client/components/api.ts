@@ -7,6 +7,12 @@ export interface APIResult {
   data?: Object | undefined
 }
 
+export interface APIError {
+  code: string,
+  message: string,
+  details?: string | null
+}
+
 export const getRuntimeConfig = async (baseUrl: String): Promise<APIResult> => {
   const url = baseUrl.replace(/\/$/, '') + '/runtime-config.json'
   let resp: Response
@@ -61,11 +67,18 @@ export const getApiResult = async (endpoint: string, method: string, payload: Ob
   if (!resp.ok) {
     // use standard HTTP status text by default
     let text = resp.statusText
-    // replace with custom error text if any
+    let code = ''
+    // replace with the API error message if any
     const rt = await resp.text().catch(() => { })
-    text = rt || text
+    try {
+      const err: APIError = JSON.parse(rt || '')
+      code = err.code
+      text = err.message || text
+    } catch (e) {
+      text = rt || text
+    }
     // dont popup expired tokens
-    if (failureMessagePrefix && !text.includes('cannot get valid email from token')) {
+    if (failureMessagePrefix && code !== 'invalid_token') {
       toastError(`${failureMessagePrefix} (${resp.status}): ${text} `)
     }
     if (resp.status === 401) {
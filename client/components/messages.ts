@@ -9,4 +9,20 @@ export interface IMessage {
   owner_last_seen: number,
   recipient_last_seen: number,
   revealed: boolean,
+  claim_veto_minutes: number,
+  claimed_on: number,
+  trusted_contacts: string[],
+  vouched_on: number,
+  incapacity_confirmations: { contact: string, confirmed_on: number }[],
+  require_acceptance: boolean,
+  accepted_on: number,
+  title: string,
+  labels: string[],
+  note: string,
+  revealed_on: number,
+  expire_after_days: number | null,
+  purge_after_reveal_days: number | null,
+  retrieved_on: number,
+  share_purged_on: number,
+  pending_invite: boolean,
 }
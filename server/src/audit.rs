@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{data_struct::AuditEntry, db};
+
+/// Actor recorded for events triggered by the server itself.
+pub const SYSTEM_ACTOR: &str = "system";
+/// Actor recorded for events triggered through the command line.
+pub const CLI_ACTOR: &str = "cli";
+
+/// Returns the storage key for the entry with the given sequence number.
+/// Keys are zero-padded so that they sort in sequence order.
+pub fn entry_id(seq: u64) -> String {
+    format!("{:020}", seq)
+}
+
+fn compute_hash(entry: &AuditEntry) -> Result<String> {
+    let unhashed = AuditEntry { hash: String::new(), ..entry.clone() };
+    let json = serde_json::to_string(&unhashed)?;
+    Ok(hex::encode(Sha256::digest(json.as_bytes())))
+}
+
+/// Links the entry to the last one in the log, setting its sequence number and
+/// hashes.
+pub fn chain(last: Option<&AuditEntry>, entry: AuditEntry) -> Result<AuditEntry> {
+    let mut entry = match last {
+        Some(l) => AuditEntry { seq: l.seq + 1, prev_hash: l.hash.clone(), ..entry },
+        None => AuditEntry { seq: 0, prev_hash: String::new(), ..entry },
+    };
+    entry.hash = compute_hash(&entry)?;
+    Ok(entry)
+}
+
+/// Checks that entries, sorted by sequence number, form an unbroken chain.
+pub fn verify_chain(entries: &[AuditEntry]) -> bool {
+    let mut prev_hash = String::new();
+    for (i, e) in entries.iter().enumerate() {
+        if e.seq != i as u64 || e.prev_hash != prev_hash {
+            return false;
+        }
+        match compute_hash(e) {
+            Ok(h) if h == e.hash => prev_hash = h,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Returns the pseudonym recorded in place of the email. Keys are per email,
+/// so deleting a user's key unlinks their entries from them.
+pub fn pseudonym(key: &str, email: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(email.as_bytes());
+    format!("anon:{}", hex::encode(&mac.finalize().into_bytes()[..16]))
+}
+
+/// Returns the users the entry refers to, as opposed to system actors.
+pub fn emails(entry: &AuditEntry) -> Vec<String> {
+    [&entry.actor, &entry.owner, &entry.recipient]
+        .into_iter()
+        .filter(|s| s.contains('@'))
+        .cloned()
+        .collect()
+}
+
+fn map_users(entry: AuditEntry, f: impl Fn(&str) -> Option<String>) -> AuditEntry {
+    AuditEntry {
+        actor: f(&entry.actor).unwrap_or_else(|| entry.actor.clone()),
+        owner: f(&entry.owner).unwrap_or_else(|| entry.owner.clone()),
+        recipient: f(&entry.recipient).unwrap_or_else(|| entry.recipient.clone()),
+        ..entry
+    }
+}
+
+/// Replaces the emails in the entry with their pseudonyms, keys map emails to
+/// their audit key.
+pub fn pseudonymize(entry: AuditEntry, keys: &BTreeMap<String, String>) -> AuditEntry {
+    map_users(entry, |s| keys.get(s).map(|k| pseudonym(k, s)))
+}
+
+/// Returns whether the log is intact, and the entries concerning the user with
+/// the pseudonyms of users who still have a key resolved to their email.
+pub async fn entries_for(dbo: &db::DB, email: &str) -> Result<(bool, Vec<AuditEntry>)> {
+    let entries = dbo.get_audit_entries().await?;
+    let chain_valid = verify_chain(&entries);
+    let names: HashMap<String, String> =
+        dbo.get_audit_keys().await?.into_iter().map(|(e, k)| (pseudonym(&k, &e), e)).collect();
+    let entries = entries
+        .into_iter()
+        .map(|e| map_users(e, |s| names.get(s).cloned()))
+        .filter(|e| e.concerns(email))
+        .collect();
+    Ok((chain_valid, entries))
+}
+
+/// Appends the entry to the log. Failures are logged and otherwise ignored so
+/// that they don't interrupt the operation being recorded.
+pub async fn record(dbo: &db::DB, entry: AuditEntry) {
+    let event = entry.event;
+    let message_id = entry.message_id.clone();
+    if let Err(e) = dbo.append_audit_entry(entry).await {
+        error!(audit_event = ?event, %message_id, "cannot append event to the audit log: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_struct::AuditEvent;
+
+    fn log() -> Vec<AuditEntry> {
+        let events = [AuditEvent::MessageCreated, AuditEvent::CheckIn, AuditEvent::MessageRevealed];
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for event in events {
+            let entry = AuditEntry::new(event, "a@x.com").with_details("details");
+            entries.push(chain(entries.last(), entry).unwrap());
+        }
+        entries
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        assert!(verify_chain(&log()));
+        assert!(verify_chain(&[]));
+    }
+
+    #[test]
+    fn edited_entry_breaks_chain() {
+        let mut entries = log();
+        entries[1].details = "edited".to_owned();
+        assert!(!verify_chain(&entries));
+        // rehashing the edited entry doesn't help, the next one links to the old hash
+        entries[1].hash = compute_hash(&entries[1]).unwrap();
+        assert!(!verify_chain(&entries));
+    }
+
+    #[test]
+    fn removed_or_reordered_entries_break_chain() {
+        let mut entries = log();
+        entries.remove(1);
+        assert!(!verify_chain(&entries));
+
+        let mut entries = log();
+        entries.swap(1, 2);
+        assert!(!verify_chain(&entries));
+
+        let mut entries = log();
+        entries.remove(0);
+        assert!(!verify_chain(&entries));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+
+use anyhow::{format_err, Result};
+use serde::Serialize;
+
+use crate::{
+    audit::{self, CLI_ACTOR},
+    config::{Args, Command, DbCommand, MessagesCommand, NotifyCommand, UsersCommand},
+    data_struct::{AuditEntry, AuditEvent, SecretMessage},
+    db,
+    handler::{message_summaries, user_summaries, MessageSummary, UserSummary},
+    notifier::{AppPushMessage, WebPusher},
+};
+
+#[derive(Serialize)]
+struct UserDetails {
+    user: UserSummary,
+    messages: Vec<MessageSummary>,
+}
+
+fn print<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Runs an admin command directly against the configured storage.
+pub async fn run(args: &Args, command: &Command) -> Result<()> {
+    let dbo = db::DBBuilder::new(args.storage, &args.storage_id()).await?;
+    match command {
+        Command::Users(UsersCommand::List) => {
+            let users = dbo.get_all_users().await?;
+            let messages = dbo.get_all_messages().await?;
+            print(&user_summaries(users, &messages))
+        }
+        Command::Users(UsersCommand::Show { email }) => {
+            let user =
+                dbo.get_user(email).await.map_err(|_| format_err!("user {} not found", email))?;
+            let messages = dbo.get_all_messages().await?;
+            let involved = messages.into_iter().filter(|(_, m)| m.involves(email)).collect();
+            let user = user_summaries(vec![user], &involved).remove(0);
+            print(&UserDetails { user, messages: message_summaries(&involved, None) })
+        }
+        Command::Messages(MessagesCommand::List { owner }) => {
+            let messages = dbo.get_all_messages().await?;
+            print(&message_summaries(&messages, owner.as_deref()))
+        }
+        Command::Messages(MessagesCommand::Pause { id, resume }) => {
+            let m =
+                dbo.get_message(id).await.map_err(|_| format_err!("message {} not found", id))?;
+            dbo.set_message_paused(id, !resume).await?;
+            let event =
+                if *resume { AuditEvent::MessageResumed } else { AuditEvent::MessagePaused };
+            let m = SecretMessage { id: id.clone(), ..m };
+            audit::record(&dbo, AuditEntry::for_message(event, CLI_ACTOR, &m)).await;
+            println!("message {} {}", id, if *resume { "resumed" } else { "paused" });
+            Ok(())
+        }
+        Command::Db(DbCommand::Check) => {
+            let issues = check(&dbo).await?;
+            for issue in issues.iter() {
+                println!("{}", issue);
+            }
+            if !issues.is_empty() {
+                return Err(format_err!("found {} integrity issues", issues.len()));
+            }
+            println!("no issues found");
+            Ok(())
+        }
+        Command::Notify(NotifyCommand::Test { email }) => {
+            let user =
+                dbo.get_user(email).await.map_err(|_| format_err!("user {} not found", email))?;
+            if user.subscription.keys.auth.is_empty() {
+                return Err(format_err!("user {} hasn't subscribed to notifications", email));
+            }
+            let push_message = AppPushMessage {
+                tag: "test".to_string(),
+                title: "Ywinby says 👋".to_string(),
+                message: "This is a test notification from the server operator.".to_string(),
+            };
+            WebPusher::new(args.push_privkey.clone(), args.previous_push_privkeys.clone())?
+                .send_message(user.subscription, push_message)
+                .await?;
+            let entry = AuditEntry {
+                recipient: email.clone(),
+                ..AuditEntry::new(AuditEvent::NotificationSent, CLI_ACTOR).with_details("test")
+            };
+            audit::record(&dbo, entry).await;
+            println!("test notification sent to {}", email);
+            Ok(())
+        }
+    }
+}
+
+/// Lists messages that refer to users that don't exist. Recipients of pending
+/// invites aren't expected to exist yet.
+async fn check(dbo: &db::DB) -> Result<Vec<String>> {
+    let users: HashSet<String> = dbo.get_all_users().await?.into_iter().map(|u| u.id).collect();
+    let messages = dbo.get_all_messages().await?;
+    let mut issues = vec![];
+    for (id, m) in messages.iter() {
+        if !users.contains(&m.owner) {
+            issues.push(format!("message {}: owner {} doesn't exist", id, m.owner));
+        }
+        if !users.contains(&m.recipient) && !m.is_pending_invite() {
+            issues.push(format!("message {}: recipient {} doesn't exist", id, m.recipient));
+        }
+        for c in m.trusted_contacts.iter().filter(|c| !users.contains(*c)) {
+            issues.push(format!("message {}: trusted contact {} doesn't exist", id, c));
+        }
+    }
+    Ok(issues)
+}
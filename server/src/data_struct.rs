@@ -1,32 +1,63 @@
 use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 pub type UserID = String;
 
 const MINIMUM_SECONDS_BETWEEN_RECIPIENT_NOTIFICATION: u64 = 86400; // 24 hrs
+const SECONDS_IN_A_DAY: u64 = 86400;
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+/// Server-wide retention defaults, used for messages that don't set their own.
+/// A value of 0 means messages are kept forever.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub expire_after_days: u64,
+    pub purge_after_reveal_days: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, ToSchema)]
 pub struct User {
     pub id: UserID,
     pub last_seen: u64,
     #[serde(default)]
     pub subscription: Subscription,
+    /// Accept test notifications from users who don't share a message with
+    /// this one.
+    #[serde(default)]
+    pub allow_test_notifications: bool,
+    /// Registered, but waiting for an admin to approve the account.
+    #[serde(default)]
+    pub pending_approval: bool,
+    /// Disabled by an admin, the user can't sign in and their messages are
+    /// paused.
+    #[serde(default)]
+    pub disabled: bool,
+    /// When a push to the subscription failed because the endpoint is gone.
+    #[serde(default)]
+    pub subscription_gone_on: u64,
+    /// When the user was asked to subscribe again with the current push key.
+    #[serde(default)]
+    pub resubscribe_requested_on: u64,
 }
 
-#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq, Hash, ToSchema)]
 pub struct Subscription {
     pub endpoint: String,
     pub keys: Keys,
+    /// Public VAPID key the subscription was created with, empty for ones
+    /// stored before keys were tracked.
+    #[serde(default)]
+    pub vapid_pubkey: String,
 }
 
-#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq, Hash, ToSchema)]
 pub struct Keys {
     pub p256dh: String,
     pub auth: String,
 }
 
-#[derive(Serialize, Default)]
+#[derive(Serialize, Default, ToSchema)]
 pub struct MessageWithLastSeen {
     pub recipient: UserID,
     pub system_share: String,
@@ -38,9 +69,32 @@ pub struct MessageWithLastSeen {
     pub id: String,
     pub owner_last_seen: u64,
     pub recipient_last_seen: u64,
+    pub claim_veto_minutes: u64,
+    pub claimed_on: u64,
+    pub trusted_contacts: Vec<UserID>,
+    pub vouched_on: u64,
+    pub incapacity_confirmations: Vec<ContactConfirmation>,
+    pub require_acceptance: bool,
+    pub accepted_on: u64,
+    pub title: String,
+    pub labels: Vec<String>,
+    pub note: String,
+    pub revealed_on: u64,
+    pub expire_after_days: Option<u64>,
+    pub purge_after_reveal_days: Option<u64>,
+    pub retrieved_on: u64,
+    pub share_purged_on: u64,
+    pub pending_invite: bool,
+    pub paused: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, ToSchema)]
+pub struct ContactConfirmation {
+    pub contact: UserID,
+    pub confirmed_on: u64,
 }
 
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug, ToSchema)]
 pub struct SecretMessage {
     pub recipient: UserID,
     pub system_share: String,
@@ -58,20 +112,188 @@ pub struct SecretMessage {
     pub revealed: bool,
     #[serde(default)]
     pub id: String,
+    /// Time given to the owner to object to a recipient's claim, 0 means the
+    /// recipient cannot file a claim on this message.
+    #[serde(default)]
+    pub claim_veto_minutes: u64,
+    #[serde(default)]
+    pub claimed_on: u64,
+    /// When a claim was last filed or vetoed, the next one has to wait a veto
+    /// window from then.
+    #[serde(default)]
+    pub claim_updated_on: u64,
+    /// Users who get notified when the owner misses check-ins, and who can
+    /// vouch for or confirm the owner's incapacity.
+    #[serde(default)]
+    pub trusted_contacts: Vec<UserID>,
+    #[serde(default)]
+    pub contacts_notified_on: u64,
+    #[serde(default)]
+    pub vouched_on: u64,
+    #[serde(default)]
+    pub incapacity_confirmations: Vec<ContactConfirmation>,
+    /// Recipient is invited on creation and should confirm that they hold
+    /// their share.
+    #[serde(default)]
+    pub require_acceptance: bool,
+    #[serde(default)]
+    pub accepted_on: u64,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Note from the owner, only disclosed to the recipient after reveal.
+    #[serde(default)]
+    pub note: String,
+    #[serde(default)]
+    pub revealed_on: u64,
+    /// Overrides the server retention policy when set, 0 keeps the message
+    /// forever.
+    #[serde(default)]
+    pub expire_after_days: Option<u64>,
+    #[serde(default)]
+    pub purge_after_reveal_days: Option<u64>,
+    /// First time the recipient fetched the system share after reveal.
+    #[serde(default)]
+    pub retrieved_on: u64,
+    /// When the system share was deleted by the retention policy.
+    #[serde(default)]
+    pub share_purged_on: u64,
+    /// Hash of the invite token sent to a recipient who wasn't registered or
+    /// subscribed yet. The message stays inactive until they are.
+    #[serde(default)]
+    pub invite_token_hash: String,
+    /// Paused by an admin, no check-ins are requested and the message isn't
+    /// revealed until it's resumed.
+    #[serde(default)]
+    pub paused: bool,
+    /// When the message was resumed or its owner re-enabled, the countdown
+    /// restarts from then.
+    #[serde(default)]
+    pub resumed_on: u64,
+    /// When the recipient deleted their account. The message is inactive
+    /// until they register and subscribe again.
+    #[serde(default)]
+    pub recipient_left_on: u64,
+}
+
+/// How a user relates to a message, used to query messages by user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageRole {
+    Owner,
+    Recipient,
+    Contact,
 }
 
 impl SecretMessage {
+    /// Whether the user is the owner, recipient or a trusted contact.
+    pub fn involves(&self, email: &str) -> bool {
+        self.owner == email
+            || self.recipient == email
+            || self.trusted_contacts.iter().any(|c| c == email)
+    }
+
+    pub fn has_role(&self, role: MessageRole, email: &str) -> bool {
+        match role {
+            MessageRole::Owner => self.owner == email,
+            MessageRole::Recipient => self.recipient == email,
+            MessageRole::Contact => self.trusted_contacts.iter().any(|c| c == email),
+        }
+    }
+
+    /// Whether the message waits for its recipient to register and subscribe.
+    pub fn is_pending_invite(&self) -> bool {
+        !self.invite_token_hash.is_empty() || self.recipient_left_on != 0
+    }
+
     pub fn should_reveal(&self, owner_last_seen: u64) -> Result<bool, SystemTimeError> {
         if self.revealed {
             return Ok(true);
         }
+        if self.is_pending_invite() || self.paused {
+            return Ok(false);
+        }
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
         let max_allowed = self.verify_every_minutes * 60 * self.max_failed_verification;
-        let reveal_time = owner_last_seen + max_allowed;
-        Ok(now >= reveal_time)
+        let reveal_time = self.last_vouched(owner_last_seen) + max_allowed;
+        Ok(now >= reveal_time
+            || self.claim_expired(owner_last_seen, now)
+            || self.incapacity_confirmed(owner_last_seen, now))
+    }
+
+    /// A vouch from a trusted contact pauses the countdown just like an owner
+    /// check-in does, up to vouch_limit. Resuming the message restarts it.
+    fn last_vouched(&self, owner_last_seen: u64) -> u64 {
+        let vouched_on = self.vouched_on.min(self.vouch_limit(owner_last_seen));
+        owner_last_seen.max(vouched_on).max(self.resumed_on)
+    }
+
+    /// Latest time a vouch still counts, one reveal period after the owner
+    /// was last seen, so that contacts can't hold the message back forever.
+    pub fn vouch_limit(&self, owner_last_seen: u64) -> u64 {
+        let max_allowed = self.verify_every_minutes * 60 * self.max_failed_verification;
+        owner_last_seen.max(self.resumed_on) + max_allowed
+    }
+
+    /// Whether the owner left a reminder unanswered since they (or anyone
+    /// vouching for them) were last seen. Contacts can only confirm
+    /// incapacity then.
+    pub fn missed_check_in(&self, owner_last_seen: u64) -> bool {
+        self.owner_notified_on > self.last_vouched(owner_last_seen)
+    }
+
+    /// When the majority of trusted contacts had confirmed incapacity since
+    /// the owner's missed check-in, if they have. A check-in or vouch cancels
+    /// the confirmations made before it.
+    pub fn incapacity_quorum_on(&self, owner_last_seen: u64) -> Option<u64> {
+        if self.trusted_contacts.is_empty() || !self.missed_check_in(owner_last_seen) {
+            return None;
+        }
+        let since = self.last_vouched(owner_last_seen);
+        let mut confirmed: Vec<u64> = self
+            .incapacity_confirmations
+            .iter()
+            .filter(|c| c.confirmed_on > since && self.trusted_contacts.contains(&c.contact))
+            .map(|c| c.confirmed_on)
+            .collect();
+        confirmed.sort_unstable();
+        confirmed.get(self.trusted_contacts.len() / 2).copied()
+    }
+
+    /// Time the owner has to object once incapacity is confirmed, one check-in
+    /// period.
+    pub fn incapacity_veto_seconds(&self) -> u64 {
+        self.verify_every_minutes * 60
+    }
+
+    /// Returns true once the veto window after the contacts' quorum has passed
+    /// without the owner checking in.
+    fn incapacity_confirmed(&self, owner_last_seen: u64, now: u64) -> bool {
+        self.incapacity_quorum_on(owner_last_seen)
+            .is_some_and(|t| now >= t + self.incapacity_veto_seconds())
+    }
+
+    pub fn is_claim_pending(&self, owner_last_seen: u64) -> bool {
+        // the owner being seen after the claim was filed counts as a veto
+        self.claimed_on != 0 && owner_last_seen < self.claimed_on
+    }
+
+    /// Whether the recipient can file another claim, at most one per veto
+    /// window so that the owner isn't flooded with them.
+    pub fn can_claim_again(&self, now: u64) -> bool {
+        now >= self.claim_updated_on + self.claim_veto_minutes * 60
+    }
+
+    fn claim_expired(&self, owner_last_seen: u64, now: u64) -> bool {
+        // the owner gets a full veto window after the message is resumed
+        self.is_claim_pending(owner_last_seen)
+            && now >= self.claimed_on.max(self.resumed_on) + self.claim_veto_minutes * 60
     }
 
     pub fn should_notify_recipient(&self, owner_last_seen: u64) -> Result<bool, SystemTimeError> {
+        if self.retrieved_on != 0 || self.share_purged_on != 0 {
+            return Ok(false);
+        }
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
         let reveal = self.should_reveal(owner_last_seen)?;
         let notify_time =
@@ -80,16 +302,316 @@ impl SecretMessage {
     }
 
     pub fn should_notify_owner(&self, owner_last_seen: u64) -> Result<bool, SystemTimeError> {
-        if self.revealed {
+        if self.revealed || self.is_pending_invite() || self.paused {
             return Ok(false);
         }
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        let newer = if owner_last_seen > self.owner_notified_on {
-            owner_last_seen
-        } else {
-            self.owner_notified_on
-        };
+        let newer = owner_last_seen.max(self.owner_notified_on).max(self.resumed_on);
         let notify_time = newer + self.verify_every_minutes * 60;
         Ok(now >= notify_time)
     }
+
+    /// Whether the unrevealed message has outlived its expiry. Messages that
+    /// are due to be revealed never expire.
+    pub fn should_expire(
+        &self,
+        policy: &RetentionPolicy,
+        owner_last_seen: u64,
+    ) -> Result<bool, SystemTimeError> {
+        let days = self.expire_after_days.unwrap_or(policy.expire_after_days);
+        if days == 0 || self.should_reveal(owner_last_seen)? {
+            return Ok(false);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(now >= self.created_ts + days * SECONDS_IN_A_DAY)
+    }
+
+    /// Whether the system share of the revealed message should be deleted,
+    /// counting from its retrieval by the recipient, or from its reveal until
+    /// then. Messages without a reveal time aren't purged.
+    pub fn should_purge_share(&self, policy: &RetentionPolicy) -> Result<bool, SystemTimeError> {
+        let days = self.purge_after_reveal_days.unwrap_or(policy.purge_after_reveal_days);
+        if !self.revealed || self.revealed_on == 0 || self.share_purged_on != 0 || days == 0 {
+            return Ok(false);
+        }
+        let since = if self.retrieved_on != 0 { self.retrieved_on } else { self.revealed_on };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(now >= since + days * SECONDS_IN_A_DAY)
+    }
+
+    pub fn should_notify_contacts(&self, owner_last_seen: u64) -> Result<bool, SystemTimeError> {
+        if self.revealed || self.paused || self.trusted_contacts.is_empty() {
+            return Ok(false);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        // owner has been pinged and hasn't responded for a whole period since
+        let missed_checkin = self.owner_notified_on > self.last_vouched(owner_last_seen)
+            && now >= self.owner_notified_on + self.verify_every_minutes * 60;
+        let notify_time = self.contacts_notified_on + self.verify_every_minutes * 60;
+        Ok(missed_checkin && now >= notify_time)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    MessageCreated,
+    MessageDeleted,
+    MessageRevealed,
+    MessageAccepted,
+    CheckIn,
+    SystemShareDisclosed,
+    NotificationSent,
+    NotificationRefused,
+    ClaimFiled,
+    ClaimVetoed,
+    OwnerVouched,
+    IncapacityConfirmed,
+    UserRegistered,
+    UserApproved,
+    UserRejected,
+    InviteSent,
+    MessageActivated,
+    MessagePaused,
+    MessageResumed,
+    UserDisabled,
+    UserEnabled,
+    SubscriptionPurged,
+    UserDeleted,
+    SystemSharePurged,
+    RecipientDetached,
+}
+
+/// An append-only audit log record. Each entry includes the hash of the one
+/// before it, so altering or removing an entry breaks the chain.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub ts: u64,
+    pub event: AuditEvent,
+    pub message_id: String,
+    pub actor: UserID,
+    pub owner: UserID,
+    pub recipient: UserID,
+    pub details: String,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    pub fn new(event: AuditEvent, actor: &str) -> Self {
+        AuditEntry {
+            seq: 0,
+            ts: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            event,
+            message_id: String::new(),
+            actor: actor.to_owned(),
+            owner: String::new(),
+            recipient: String::new(),
+            details: String::new(),
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    pub fn for_message(event: AuditEvent, actor: &str, message: &SecretMessage) -> Self {
+        AuditEntry {
+            message_id: message.id.clone(),
+            owner: message.owner.clone(),
+            recipient: message.recipient.clone(),
+            ..Self::new(event, actor)
+        }
+    }
+
+    pub fn with_details(self, details: &str) -> Self {
+        AuditEntry { details: details.to_owned(), ..self }
+    }
+
+    pub fn concerns(&self, email: &str) -> bool {
+        self.owner == email || self.recipient == email || self.actor == email
+    }
+}
+
+/// Key used to pseudonymize a user in the audit log, stored apart from the log
+/// and deleted with the account.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditKey {
+    pub id: UserID,
+    pub key: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// Checked every hour, revealed after three missed check-ins.
+    fn message() -> SecretMessage {
+        SecretMessage { verify_every_minutes: 60, max_failed_verification: 3, ..Default::default() }
+    }
+
+    #[test]
+    fn reveals_after_missed_check_ins() {
+        let m = message();
+        assert!(!m.should_reveal(now() - 2 * HOUR).unwrap());
+        assert!(m.should_reveal(now() - 3 * HOUR).unwrap());
+        let revealed = SecretMessage { revealed: true, ..message() };
+        assert!(revealed.should_reveal(now()).unwrap());
+    }
+
+    #[test]
+    fn claim_is_vetoed_by_owner_check_in() {
+        let m = SecretMessage { claim_veto_minutes: 30, claimed_on: now() - 60, ..message() };
+        assert!(m.is_claim_pending(now() - HOUR));
+        assert!(!m.is_claim_pending(now()));
+        assert!(!message().is_claim_pending(now() - HOUR));
+    }
+
+    #[test]
+    fn claim_reveals_after_veto_window() {
+        let pending = SecretMessage { claim_veto_minutes: 30, claimed_on: now() - 60, ..message() };
+        assert!(!pending.should_reveal(now() - HOUR).unwrap());
+
+        let expired = SecretMessage { claimed_on: now() - HOUR, ..pending.clone() };
+        assert!(expired.should_reveal(now() - 2 * HOUR).unwrap());
+        // seen after the claim was filed
+        assert!(!expired.should_reveal(now() - 60).unwrap());
+    }
+
+    #[test]
+    fn claims_wait_a_veto_window() {
+        let m = SecretMessage { claim_veto_minutes: 30, claim_updated_on: now() - 60, ..message() };
+        assert!(!m.can_claim_again(now()));
+        assert!(m.can_claim_again(now() + 30 * 60));
+        assert!(message().can_claim_again(now()));
+    }
+
+    fn with_contacts(confirmed_on: &[(&str, u64)]) -> SecretMessage {
+        SecretMessage {
+            trusted_contacts: vec!["a@x.com".into(), "b@x.com".into(), "c@x.com".into()],
+            incapacity_confirmations: confirmed_on
+                .iter()
+                .map(|(c, t)| ContactConfirmation { contact: c.to_string(), confirmed_on: *t })
+                .collect(),
+            ..message()
+        }
+    }
+
+    /// Checked every hour and revealed after a day, reminded two hours ago
+    /// and confirmed by the contacts at the given times.
+    fn confirmed_by(confirmed_on: &[(&str, u64)]) -> SecretMessage {
+        SecretMessage {
+            max_failed_verification: 24,
+            owner_notified_on: now() - 2 * HOUR,
+            ..with_contacts(confirmed_on)
+        }
+    }
+
+    #[test]
+    fn incapacity_needs_a_majority_of_contacts() {
+        let (seen, at) = (now() - 3 * HOUR, now() - HOUR);
+        assert_eq!(confirmed_by(&[]).incapacity_quorum_on(seen), None);
+        assert_eq!(confirmed_by(&[("a@x.com", at)]).incapacity_quorum_on(seen), None);
+        let majority = confirmed_by(&[("a@x.com", at - 60), ("b@x.com", at)]);
+        assert_eq!(majority.incapacity_quorum_on(seen), Some(at));
+        // confirmations of users who aren't contacts don't count
+        let outsider = confirmed_by(&[("a@x.com", at), ("z@x.com", at)]);
+        assert_eq!(outsider.incapacity_quorum_on(seen), None);
+    }
+
+    #[test]
+    fn incapacity_reveals_after_veto_window() {
+        let seen = now() - 3 * HOUR;
+        let recent = confirmed_by(&[("a@x.com", now() - 60), ("b@x.com", now() - 60)]);
+        assert!(!recent.should_reveal(seen).unwrap());
+        let past = confirmed_by(&[("a@x.com", now() - HOUR), ("b@x.com", now() - HOUR)]);
+        assert!(past.should_reveal(seen).unwrap());
+    }
+
+    #[test]
+    fn check_in_after_confirmations_prevents_reveal() {
+        let confirmed = confirmed_by(&[("a@x.com", now() - HOUR), ("b@x.com", now() - HOUR)]);
+        assert!(confirmed.should_reveal(now() - 3 * HOUR).unwrap());
+        assert!(!confirmed.should_reveal(now() - 60).unwrap());
+        assert_eq!(confirmed.incapacity_quorum_on(now() - 60), None);
+        let vouched = SecretMessage { vouched_on: now() - 60, ..confirmed };
+        assert!(!vouched.should_reveal(now() - 3 * HOUR).unwrap());
+    }
+
+    #[test]
+    fn incapacity_needs_a_missed_check_in() {
+        // confirmed, but the owner checked in after the last reminder
+        let (seen, at) = (now() - 90 * 60, now() - HOUR);
+        let answered = confirmed_by(&[("a@x.com", at), ("b@x.com", at)]);
+        assert!(!answered.missed_check_in(seen));
+        assert_eq!(answered.incapacity_quorum_on(seen), None);
+        let never_reminded = SecretMessage { owner_notified_on: 0, ..answered };
+        assert_eq!(never_reminded.incapacity_quorum_on(now() - 3 * HOUR), None);
+    }
+
+    #[test]
+    fn vouching_delays_reveal_up_to_a_limit() {
+        let seen = now() - 3 * HOUR;
+        let vouched = SecretMessage { vouched_on: now() - HOUR, ..message() };
+        assert!(!vouched.should_reveal(seen).unwrap());
+        // vouches count at most one reveal period past the owner's check-in
+        let seen = now() - 6 * HOUR;
+        let late = SecretMessage { vouched_on: now() - 60, ..message() };
+        assert_eq!(late.vouch_limit(seen), seen + 3 * HOUR);
+        assert!(late.should_reveal(seen).unwrap());
+    }
+
+    #[test]
+    fn contacts_are_notified_after_an_unanswered_reminder() {
+        let seen = now() - 3 * HOUR;
+        let reminded = SecretMessage { owner_notified_on: now() - HOUR, ..with_contacts(&[]) };
+        assert!(reminded.should_notify_contacts(seen).unwrap());
+        let just_reminded = SecretMessage { owner_notified_on: now() - 60, ..reminded.clone() };
+        assert!(!just_reminded.should_notify_contacts(seen).unwrap());
+        let notified = SecretMessage { contacts_notified_on: now() - 60, ..reminded.clone() };
+        assert!(!notified.should_notify_contacts(seen).unwrap());
+        let no_contacts = SecretMessage { owner_notified_on: now() - HOUR, ..message() };
+        assert!(!no_contacts.should_notify_contacts(seen).unwrap());
+        // the owner checked in after the reminder
+        assert!(!reminded.should_notify_contacts(now() - 60).unwrap());
+    }
+
+    const DAY: u64 = SECONDS_IN_A_DAY;
+
+    #[test]
+    fn unrevealed_messages_expire() {
+        let policy = RetentionPolicy { expire_after_days: 2, purge_after_reveal_days: 0 };
+        let old = SecretMessage { created_ts: now() - 3 * DAY, ..message() };
+        assert!(old.should_expire(&policy, now()).unwrap());
+        let recent = SecretMessage { created_ts: now() - DAY, ..message() };
+        assert!(!recent.should_expire(&policy, now()).unwrap());
+        // due to be revealed
+        assert!(!old.should_expire(&policy, now() - 3 * HOUR).unwrap());
+        let kept = SecretMessage { expire_after_days: Some(0), ..old.clone() };
+        assert!(!kept.should_expire(&policy, now()).unwrap());
+        assert!(!old.should_expire(&RetentionPolicy::default(), now()).unwrap());
+    }
+
+    #[test]
+    fn system_share_is_purged_after_reveal() {
+        let policy = RetentionPolicy { expire_after_days: 0, purge_after_reveal_days: 2 };
+        let revealed = SecretMessage { revealed: true, revealed_on: now() - 3 * DAY, ..message() };
+        assert!(revealed.should_purge_share(&policy).unwrap());
+        // counted from retrieval once the recipient fetched it
+        let retrieved = SecretMessage { retrieved_on: now() - DAY, ..revealed.clone() };
+        assert!(!retrieved.should_purge_share(&policy).unwrap());
+        let purged = SecretMessage { share_purged_on: now() - DAY, ..revealed.clone() };
+        assert!(!purged.should_purge_share(&policy).unwrap());
+        let legacy = SecretMessage { revealed_on: 0, ..revealed.clone() };
+        assert!(!legacy.should_purge_share(&policy).unwrap());
+        let kept = SecretMessage { purge_after_reveal_days: Some(0), ..revealed.clone() };
+        assert!(!kept.should_purge_share(&policy).unwrap());
+        let unrevealed = SecretMessage { revealed: false, ..revealed };
+        assert!(!unrevealed.should_purge_share(&policy).unwrap());
+    }
 }
@@ -1,80 +1,340 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt};
+
+use tracing::instrument;
 
 pub mod firestore;
 pub mod jfs_store;
 
-use crate::data_struct::{MessageWithLastSeen, SecretMessage, Subscription, User};
+use crate::{
+    data_struct::{
+        AuditEntry, MessageRole, MessageWithLastSeen, SecretMessage, Subscription, User,
+    },
+    metrics,
+};
 
 type DBResult<T> = anyhow::Result<T>;
 
+/// Errors caused by the request rather than the storage backend, so that they
+/// can be reported back to clients.
+#[derive(Debug)]
+pub enum DBError {
+    Invalid(String),
+    MessageNotFound,
+}
+
+impl fmt::Display for DBError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DBError::Invalid(m) => write!(f, "{}", m),
+            DBError::MessageNotFound => write!(f, "message not found"),
+        }
+    }
+}
+
+impl std::error::Error for DBError {}
+
+impl DBError {
+    pub fn invalid(message: &str) -> anyhow::Error {
+        DBError::Invalid(message.to_owned()).into()
+    }
+}
+
 pub enum DB {
     Firestore { storage: firestore::Storage },
     Json { storage: jfs_store::Storage },
 }
 
 impl DB {
+    #[instrument(skip_all)]
+    pub async fn check_connection(&self) -> DBResult<()> {
+        let _timer = metrics::storage_timer("check_connection");
+        match self {
+            DB::Firestore { storage } => storage.check_connection().await,
+            DB::Json { storage } => storage.check_connection().await,
+        }
+    }
+    #[instrument(skip_all)]
     pub async fn put_user(&self, user: User) -> DBResult<()> {
+        let _timer = metrics::storage_timer("put_user");
         match self {
             DB::Firestore { storage } => storage.put_user(user).await,
             DB::Json { storage } => storage.put_user(user).await,
         }
     }
+    #[instrument(skip_all)]
     pub async fn get_user(&self, id: &str) -> DBResult<User> {
+        let _timer = metrics::storage_timer("get_user");
         match self {
             DB::Firestore { storage } => storage.get_user(id).await,
             DB::Json { storage } => storage.get_user(id).await,
         }
     }
-    pub async fn put_message(&self, message: SecretMessage) -> DBResult<()> {
+    #[instrument(skip_all)]
+    pub async fn get_all_users(&self) -> DBResult<Vec<User>> {
+        let _timer = metrics::storage_timer("get_all_users");
+        match self {
+            DB::Firestore { storage } => storage.get_all_users().await,
+            DB::Json { storage } => storage.get_all_users().await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn delete_user(&self, id: &str) -> DBResult<()> {
+        let _timer = metrics::storage_timer("delete_user");
+        match self {
+            DB::Firestore { storage } => storage.delete_user(id).await,
+            DB::Json { storage } => storage.delete_user(id).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn put_message(&self, message: SecretMessage) -> DBResult<String> {
+        let _timer = metrics::storage_timer("put_message");
         match self {
             DB::Firestore { storage } => storage.put_message(message).await,
             DB::Json { storage } => storage.put_message(message).await,
         }
     }
+    #[instrument(skip_all)]
     pub async fn update_message_notified_on(&self, id: &str, email: &str) -> DBResult<()> {
+        let _timer = metrics::storage_timer("update_message_notified_on");
         match self {
             DB::Firestore { storage } => storage.update_message_notified_on(id, email).await,
             DB::Json { storage } => storage.update_message_notified_on(id, email).await,
         }
     }
+    #[instrument(skip_all)]
+    pub async fn set_message_claimed_on(&self, id: &str, claimed_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_claimed_on");
+        match self {
+            DB::Firestore { storage } => storage.set_message_claimed_on(id, claimed_on).await,
+            DB::Json { storage } => storage.set_message_claimed_on(id, claimed_on).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn set_message_vouched_on(&self, id: &str, vouched_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_vouched_on");
+        match self {
+            DB::Firestore { storage } => storage.set_message_vouched_on(id, vouched_on).await,
+            DB::Json { storage } => storage.set_message_vouched_on(id, vouched_on).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn set_message_incapacity_confirmed_on(
+        &self,
+        id: &str,
+        contact: &str,
+        confirmed_on: u64,
+    ) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_incapacity_confirmed_on");
+        match self {
+            DB::Firestore { storage } => {
+                storage.set_message_incapacity_confirmed_on(id, contact, confirmed_on).await
+            }
+            DB::Json { storage } => {
+                storage.set_message_incapacity_confirmed_on(id, contact, confirmed_on).await
+            }
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn set_message_accepted_on(&self, id: &str, accepted_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_accepted_on");
+        match self {
+            DB::Firestore { storage } => storage.set_message_accepted_on(id, accepted_on).await,
+            DB::Json { storage } => storage.set_message_accepted_on(id, accepted_on).await,
+        }
+    }
+    /// Resuming a paused message restarts its countdown.
+    #[instrument(skip_all)]
+    pub async fn set_message_paused(&self, id: &str, paused: bool) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_paused");
+        match self {
+            DB::Firestore { storage } => storage.set_message_paused(id, paused).await,
+            DB::Json { storage } => storage.set_message_paused(id, paused).await,
+        }
+    }
+    /// Restarts the countdown of a message.
+    #[instrument(skip_all)]
+    pub async fn set_message_resumed_on(&self, id: &str, resumed_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_resumed_on");
+        match self {
+            DB::Firestore { storage } => storage.set_message_resumed_on(id, resumed_on).await,
+            DB::Json { storage } => storage.set_message_resumed_on(id, resumed_on).await,
+        }
+    }
+    /// Sets the reveal time of a message revealed before it was recorded.
+    #[instrument(skip_all)]
+    pub async fn set_message_revealed_on(&self, id: &str, revealed_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("set_message_revealed_on");
+        match self {
+            DB::Firestore { storage } => storage.set_message_revealed_on(id, revealed_on).await,
+            DB::Json { storage } => storage.set_message_revealed_on(id, revealed_on).await,
+        }
+    }
+    /// Deletes the system share of a message, keeping its metadata.
+    #[instrument(skip_all)]
+    pub async fn purge_system_share(&self, id: &str, purged_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("purge_system_share");
+        match self {
+            DB::Firestore { storage } => storage.purge_system_share(id, purged_on).await,
+            DB::Json { storage } => storage.purge_system_share(id, purged_on).await,
+        }
+    }
+    /// Removes the user from the message's trusted contacts, along with their
+    /// incapacity confirmations.
+    #[instrument(skip_all)]
+    pub async fn remove_trusted_contact(&self, id: &str, email: &str) -> DBResult<()> {
+        let _timer = metrics::storage_timer("remove_trusted_contact");
+        match self {
+            DB::Firestore { storage } => storage.remove_trusted_contact(id, email).await,
+            DB::Json { storage } => storage.remove_trusted_contact(id, email).await,
+        }
+    }
+    /// Clears the invite of a message whose recipient has subscribed.
+    #[instrument(skip_all)]
+    pub async fn activate_message(&self, id: &str) -> DBResult<()> {
+        let _timer = metrics::storage_timer("activate_message");
+        match self {
+            DB::Firestore { storage } => storage.activate_message(id).await,
+            DB::Json { storage } => storage.activate_message(id).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn detach_recipient(&self, id: &str, left_on: u64) -> DBResult<()> {
+        let _timer = metrics::storage_timer("detach_recipient");
+        match self {
+            DB::Firestore { storage } => storage.detach_recipient(id, left_on).await,
+            DB::Json { storage } => storage.detach_recipient(id, left_on).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn get_message(&self, id: &str) -> DBResult<SecretMessage> {
+        let _timer = metrics::storage_timer("get_message");
+        match self {
+            DB::Firestore { storage } => storage.get_message(id).await,
+            DB::Json { storage } => storage.get_message(id).await,
+        }
+    }
+    #[instrument(skip_all)]
     pub async fn set_message_revealed_if_needed(&self, id: &str) -> DBResult<bool> {
+        let _timer = metrics::storage_timer("set_message_revealed_if_needed");
         match self {
             DB::Firestore { storage } => storage.set_message_revealed_if_needed(id).await,
             DB::Json { storage } => storage.set_message_revealed_if_needed(id).await,
         }
     }
+    #[instrument(skip_all)]
     pub async fn get_messages_for_email(
         &self,
         email: String,
     ) -> DBResult<Vec<MessageWithLastSeen>> {
+        let _timer = metrics::storage_timer("get_messages_for_email");
         match self {
             DB::Firestore { storage } => storage.get_messages_for_email(email).await,
             DB::Json { storage } => storage.get_messages_for_email(email).await,
         }
     }
+    #[instrument(skip_all)]
+    pub async fn get_message_for_email(
+        &self,
+        email: String,
+        id: &str,
+    ) -> DBResult<MessageWithLastSeen> {
+        let _timer = metrics::storage_timer("get_message_for_email");
+        match self {
+            DB::Firestore { storage } => storage.get_message_for_email(email, id).await,
+            DB::Json { storage } => storage.get_message_for_email(email, id).await,
+        }
+    }
+    #[instrument(skip_all)]
     pub async fn delete_message_from_email(
         &self,
         email: String,
         message_id: String,
     ) -> DBResult<()> {
+        let _timer = metrics::storage_timer("delete_message_from_email");
         match self {
             DB::Firestore { storage } => storage.delete_message_from_email(email, message_id).await,
             DB::Json { storage } => storage.delete_message_from_email(email, message_id).await,
         }
     }
+    #[instrument(skip_all)]
+    pub async fn delete_message(&self, id: &str) -> DBResult<()> {
+        let _timer = metrics::storage_timer("delete_message");
+        match self {
+            DB::Firestore { storage } => storage.delete_message(id).await,
+            DB::Json { storage } => storage.delete_message(id).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn append_audit_entry(&self, entry: AuditEntry) -> DBResult<()> {
+        let _timer = metrics::storage_timer("append_audit_entry");
+        match self {
+            DB::Firestore { storage } => storage.append_audit_entry(entry).await,
+            DB::Json { storage } => storage.append_audit_entry(entry).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn get_audit_keys(&self) -> DBResult<BTreeMap<String, String>> {
+        let _timer = metrics::storage_timer("get_audit_keys");
+        match self {
+            DB::Firestore { storage } => storage.get_audit_keys().await,
+            DB::Json { storage } => storage.get_audit_keys().await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn delete_audit_key(&self, email: &str) -> DBResult<()> {
+        let _timer = metrics::storage_timer("delete_audit_key");
+        match self {
+            DB::Firestore { storage } => storage.delete_audit_key(email).await,
+            DB::Json { storage } => storage.delete_audit_key(email).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn get_audit_entries(&self) -> DBResult<Vec<AuditEntry>> {
+        let _timer = metrics::storage_timer("get_audit_entries");
+        match self {
+            DB::Firestore { storage } => storage.get_audit_entries().await,
+            DB::Json { storage } => storage.get_audit_entries().await,
+        }
+    }
+    #[instrument(skip_all)]
     pub async fn get_all_messages(&self) -> DBResult<BTreeMap<String, SecretMessage>> {
+        let _timer = metrics::storage_timer("get_all_messages");
         match self {
             DB::Firestore { storage } => storage.get_all_messages().await,
             DB::Json { storage } => storage.get_all_messages().await,
         }
     }
+    #[instrument(skip_all)]
+    pub async fn get_messages_by_role(
+        &self,
+        role: MessageRole,
+        email: &str,
+    ) -> DBResult<BTreeMap<String, SecretMessage>> {
+        let _timer = metrics::storage_timer("get_messages_by_role");
+        match self {
+            DB::Firestore { storage } => storage.get_messages_by_role(role, email).await,
+            DB::Json { storage } => storage.get_messages_by_role(role, email).await,
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn shares_message(&self, email: &str, other: &str) -> DBResult<bool> {
+        let _timer = metrics::storage_timer("shares_message");
+        match self {
+            DB::Firestore { storage } => storage.shares_message(email, other).await,
+            DB::Json { storage } => storage.shares_message(email, other).await,
+        }
+    }
+    #[instrument(skip_all)]
     pub async fn unsubscribe_user(&self, email: String) -> DBResult<()> {
+        let _timer = metrics::storage_timer("unsubscribe_user");
         match self {
             DB::Firestore { storage } => storage.unsubscribe_user(email).await,
             DB::Json { storage } => storage.unsubscribe_user(email).await,
         }
     }
+    #[instrument(skip_all)]
     pub async fn subscribe_user(&self, email: String, sub: Subscription) -> DBResult<()> {
+        let _timer = metrics::storage_timer("subscribe_user");
         match self {
             DB::Firestore { storage } => storage.subscribe_user(email, sub).await,
             DB::Json { storage } => storage.subscribe_user(email, sub).await,
@@ -82,7 +342,8 @@ impl DB {
     }
 }
 
-#[derive(clap::ValueEnum, Clone, PartialEq, Copy, Debug)]
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, PartialEq, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
 pub enum StorageType {
     Firestore,
     Json,
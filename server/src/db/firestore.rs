@@ -5,16 +5,26 @@ use std::{
 
 use anyhow::{anyhow, format_err};
 use firestore::*;
-use log::info;
+use tracing::{debug, error, info};
 use uuid::Uuid;
 
-use super::DBResult;
-use crate::data_struct::{MessageWithLastSeen, SecretMessage, Subscription, User};
+use super::{DBError, DBResult};
+use crate::{
+    audit::{self, SYSTEM_ACTOR},
+    data_struct::{
+        AuditEntry, AuditEvent, AuditKey, ContactConfirmation, MessageRole, MessageWithLastSeen,
+        SecretMessage, Subscription, User,
+    },
+    invite,
+    telemetry::redact,
+};
 
 pub struct Storage {
     db: FirestoreDb,
     user_coll: String,
     message_coll: String,
+    audit_coll: String,
+    audit_key_coll: String,
 }
 
 impl Storage {
@@ -24,8 +34,15 @@ impl Storage {
             db: fdb,
             user_coll: "users".to_string(),
             message_coll: "messages".to_string(),
+            audit_coll: "audit".to_string(),
+            audit_key_coll: "audit_keys".to_string(),
         })
     }
+    pub async fn check_connection(&self) -> DBResult<()> {
+        let _: Vec<User> =
+            self.db.fluent().select().from(self.user_coll.as_str()).limit(1).obj().query().await?;
+        Ok(())
+    }
     pub async fn put_user(&self, user: User) -> DBResult<()> {
         let res: Result<User, errors::FirestoreError> = self
             .db
@@ -49,28 +66,58 @@ impl Storage {
             .into(&self.user_coll)
             .document_id(&user.id)
             .object(&user)
-            .execute()
+            .execute::<()>()
             .await?;
 
-        info!("user upserted, Id: {}", user.id);
+        debug!(user = %redact(&user.id), "user upserted");
         Ok(())
     }
     pub async fn get_user(&self, id: &str) -> DBResult<User> {
         let m = self.db.fluent().select().by_id_in(&self.user_coll).obj().one(id).await?;
         m.ok_or_else(|| format_err!("cannot find user"))
     }
-    pub async fn put_message(&self, mut message: SecretMessage) -> DBResult<()> {
+    pub async fn get_all_users(&self) -> DBResult<Vec<User>> {
+        let users: Vec<User> =
+            self.db.fluent().select().from(self.user_coll.as_str()).obj().query().await?;
+        Ok(users)
+    }
+    pub async fn delete_user(&self, id: &str) -> DBResult<()> {
+        self.db.fluent().delete().from(&self.user_coll).document_id(id).execute().await?;
+        Ok(())
+    }
+    pub async fn put_message(&self, mut message: SecretMessage) -> DBResult<String> {
         if message.owner.is_empty() {
-            return Err(anyhow!("owner must not be empty"));
+            return Err(DBError::invalid("owner must not be empty"));
         }
         if message.max_failed_verification < 1 || message.max_failed_verification > 9 {
-            return Err(anyhow!("maximum consecutive failure should be between 1 and 9"));
+            return Err(DBError::invalid("maximum consecutive failure should be between 1 and 9"));
         }
         if message.verify_every_minutes < 1 || message.verify_every_minutes > 4336204 {
-            return Err(anyhow!(
-                "maximum time between verification should be between 1 minute and 99 months"
+            return Err(DBError::invalid(
+                "maximum time between verification should be between 1 minute and 99 months",
+            ));
+        }
+        if message.claim_veto_minutes > 4336204 {
+            return Err(DBError::invalid("claim veto window should be less than 99 months"));
+        }
+        if message.title.chars().count() > 100 {
+            return Err(DBError::invalid("title should be at most 100 characters"));
+        }
+        if message.labels.len() > 10 || message.labels.iter().any(|l| l.chars().count() > 32) {
+            return Err(DBError::invalid(
+                "a message can have at most 10 labels of up to 32 characters",
             ));
         }
+        if message.note.chars().count() > 1000 {
+            return Err(DBError::invalid("note should be at most 1000 characters"));
+        }
+        let days = [message.expire_after_days, message.purge_after_reveal_days];
+        if days.iter().flatten().any(|d| *d > 36500) {
+            return Err(DBError::invalid("retention period should be at most 100 years"));
+        }
+        if message.trusted_contacts.len() > 9 {
+            return Err(DBError::invalid("a message can have at most 9 trusted contacts"));
+        }
         if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
             message.created_ts = now.as_secs();
         } else {
@@ -85,11 +132,11 @@ impl Storage {
             .into(&self.message_coll)
             .document_id(id)
             .object(&message)
-            .execute()
+            .execute::<()>()
             .await?;
 
-        info!("message upserted, Id: {}", id);
-        Ok(())
+        debug!(message_id = %id, "message upserted");
+        Ok(id.to_string())
     }
     pub async fn update_message_notified_on(&self, id: &str, email: &str) -> DBResult<()> {
         let mut message: SecretMessage = self.get_message(id).await?;
@@ -98,6 +145,8 @@ impl Storage {
                 message.recipient_notified_on = now.as_secs();
             } else if email == message.owner {
                 message.owner_notified_on = now.as_secs();
+            } else if message.trusted_contacts.iter().any(|c| c == email) {
+                message.contacts_notified_on = now.as_secs();
             }
             self.db
                 .fluent()
@@ -105,12 +154,196 @@ impl Storage {
                 .in_col(&self.message_coll)
                 .document_id(id)
                 .object(&message)
-                .execute()
+                .execute::<()>()
                 .await?;
         }
         Ok(())
     }
 
+    pub async fn set_message_claimed_on(&self, id: &str, claimed_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.claimed_on = claimed_on;
+        message.claim_updated_on =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_message_vouched_on(&self, id: &str, vouched_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.vouched_on = vouched_on;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_message_incapacity_confirmed_on(
+        &self,
+        id: &str,
+        contact: &str,
+        confirmed_on: u64,
+    ) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.incapacity_confirmations.retain(|c| c.contact != contact);
+        message
+            .incapacity_confirmations
+            .push(ContactConfirmation { contact: contact.to_owned(), confirmed_on });
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_message_accepted_on(&self, id: &str, accepted_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.accepted_on = accepted_on;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_message_paused(&self, id: &str, paused: bool) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        if message.paused && !paused {
+            message.resumed_on = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        }
+        message.paused = paused;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_message_resumed_on(&self, id: &str, resumed_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.resumed_on = resumed_on;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_message_revealed_on(&self, id: &str, revealed_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.revealed_on = revealed_on;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn purge_system_share(&self, id: &str, purged_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.system_share = String::new();
+        message.share_purged_on = purged_on;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_trusted_contact(&self, id: &str, email: &str) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.trusted_contacts.retain(|c| c != email);
+        message.incapacity_confirmations.retain(|c| c.contact != email);
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn activate_message(&self, id: &str) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.invite_token_hash = String::new();
+        message.recipient_left_on = 0;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn detach_recipient(&self, id: &str, left_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.recipient_left_on = left_on;
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn set_message_retrieved_on(&self, id: &str) -> DBResult<u64> {
+        let mut message: SecretMessage = self.get_message(id).await?;
+        message.retrieved_on = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.message_coll)
+            .document_id(id)
+            .object(&message)
+            .execute::<()>()
+            .await?;
+        Ok(message.retrieved_on)
+    }
+
     pub async fn set_message_revealed_if_needed(&self, id: &str) -> DBResult<bool> {
         let mut m = self.get_message(id).await?;
         if m.revealed {
@@ -119,22 +352,29 @@ impl Storage {
         // not revealed yet in db
         let owner = self.get_user(&m.owner).await?;
         if let Ok(r) = m.should_reveal(owner.last_seen) {
-            if r {
+            // a disabled owner's messages are paused
+            if r && !owner.disabled {
                 m.revealed = true;
+                m.revealed_on =
+                    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
                 self.db
                     .fluent()
                     .update()
                     .in_col(&self.message_coll)
                     .document_id(id)
                     .object(&m)
-                    .execute()
+                    .execute::<()>()
                     .await?;
+                let entry = AuditEntry::for_message(AuditEvent::MessageRevealed, SYSTEM_ACTOR, &m);
+                if let Err(e) = self.append_audit_entry(entry).await {
+                    error!(message_id = %id, "cannot record reveal in audit log: {}", e);
+                }
                 return Ok(true);
             }
         }
         Ok(false)
     }
-    async fn get_message(&self, id: &str) -> DBResult<SecretMessage> {
+    pub async fn get_message(&self, id: &str) -> DBResult<SecretMessage> {
         let m = self.db.fluent().select().by_id_in(&self.message_coll).obj().one(id).await?;
         m.ok_or_else(|| format_err!("cannot find message"))
     }
@@ -146,40 +386,100 @@ impl Storage {
             .get_all_messages()
             .await?
             .into_iter()
-            .filter(|x| (x.1.owner == email || x.1.recipient == email))
+            .filter(|x| {
+                x.1.owner == email
+                    || x.1.recipient == email
+                    || x.1.trusted_contacts.contains(&email)
+            })
             .collect();
 
         let mut out: Vec<MessageWithLastSeen> = Vec::new();
         for (k, v) in messages {
-            let owner = self.get_user(&v.owner).await?;
-            let recipient = self.get_user(&v.recipient).await?;
-            let mut m = MessageWithLastSeen {
-                id: k.to_owned(),
-                created_ts: v.created_ts,
-                owner: owner.id,
-                recipient: recipient.id,
-                system_share: "".to_owned(),
-                verify_every_minutes: v.verify_every_minutes,
-                max_failed_verification: v.max_failed_verification,
-                owner_last_seen: owner.last_seen,
-                recipient_last_seen: recipient.last_seen,
-                revealed: v.revealed,
-            };
+            out.push(self.with_last_seen(&email, &k, v).await?);
+        }
+        Ok(out)
+    }
+
+    pub async fn get_message_for_email(
+        &self,
+        email: String,
+        id: &str,
+    ) -> DBResult<MessageWithLastSeen> {
+        let v = self.get_message(id).await.map_err(|_| DBError::MessageNotFound)?;
+        if !v.involves(&email) {
+            return Err(DBError::MessageNotFound.into());
+        }
+        self.with_last_seen(&email, id, v).await
+    }
 
-            // first set revealed on db if needed, this flag should only change from false
-            // -> true once
-            m.revealed = self.set_message_revealed_if_needed(&k).await?;
+    /// Adds the owner's and recipient's last seen times to the message, and
+    /// discloses what the user may see of it.
+    async fn with_last_seen(
+        &self,
+        email: &str,
+        k: &str,
+        v: SecretMessage,
+    ) -> DBResult<MessageWithLastSeen> {
+        let owner = self.get_user(&v.owner).await?;
+        // invited recipients may not be registered yet
+        let recipient_last_seen =
+            self.get_user(&v.recipient).await.map(|u| u.last_seen).unwrap_or_default();
+        let mut m = MessageWithLastSeen {
+            id: k.to_owned(),
+            created_ts: v.created_ts,
+            owner: owner.id,
+            recipient: v.recipient.clone(),
+            system_share: "".to_owned(),
+            verify_every_minutes: v.verify_every_minutes,
+            max_failed_verification: v.max_failed_verification,
+            owner_last_seen: owner.last_seen,
+            recipient_last_seen,
+            revealed: v.revealed,
+            claim_veto_minutes: v.claim_veto_minutes,
+            claimed_on: v.claimed_on,
+            trusted_contacts: v.trusted_contacts.clone(),
+            vouched_on: v.vouched_on,
+            incapacity_confirmations: v.incapacity_confirmations.clone(),
+            require_acceptance: v.require_acceptance,
+            accepted_on: v.accepted_on,
+            title: v.title.clone(),
+            labels: v.labels.clone(),
+            note: "".to_owned(),
+            revealed_on: v.revealed_on,
+            expire_after_days: v.expire_after_days,
+            purge_after_reveal_days: v.purge_after_reveal_days,
+            retrieved_on: v.retrieved_on,
+            share_purged_on: v.share_purged_on,
+            pending_invite: v.is_pending_invite(),
+            paused: v.paused,
+        };
 
-            if email == v.owner {
-                m.system_share = v.system_share.clone();
-            }
-            // disclose system share to recipient if revealed is true
-            if email == v.recipient && m.revealed {
-                m.system_share = v.system_share.clone();
+        // first set revealed on db if needed, this flag should only change from false
+        // -> true once
+        m.revealed = self.set_message_revealed_if_needed(k).await?;
+
+        if email == v.owner {
+            m.system_share = v.system_share.clone();
+            m.note = v.note.clone();
+        }
+        // disclose system share and note to recipient if revealed is true
+        if email == v.recipient && m.revealed {
+            m.system_share = v.system_share.clone();
+            m.note = v.note.clone();
+            if v.retrieved_on == 0 {
+                m.retrieved_on = self.set_message_retrieved_on(k).await?;
+                info!(
+                    message_id = %k,
+                    event = "system_share_disclosed",
+                    "system share retrieved by recipient",
+                );
+                let entry = AuditEntry::for_message(AuditEvent::SystemShareDisclosed, email, &v);
+                if let Err(e) = self.append_audit_entry(entry).await {
+                    error!(message_id = %k, "cannot record disclosure in audit log: {}", e);
+                }
             }
-            out.push(m);
         }
-        Ok(out)
+        Ok(m)
     }
 
     pub async fn delete_message_from_email(
@@ -191,7 +491,7 @@ impl Storage {
             .get_all_messages()
             .await?
             .into_iter()
-            .filter(|x| (x.1.owner == email || x.1.recipient == email))
+            .filter(|x| x.1.owner == email || x.1.recipient == email)
             .collect();
 
         if messages.contains_key(message_id.as_str()) {
@@ -212,7 +512,102 @@ impl Storage {
                 return Ok(());
             }
         }
-        Err(anyhow!("message not found"))
+        Err(DBError::MessageNotFound.into())
+    }
+
+    pub async fn delete_message(&self, id: &str) -> DBResult<()> {
+        self.db.fluent().delete().from(&self.message_coll).document_id(id).execute().await?;
+        Ok(())
+    }
+
+    async fn audit_key(&self, email: &str) -> DBResult<String> {
+        let existing: Option<AuditKey> =
+            self.db.fluent().select().by_id_in(&self.audit_key_coll).obj().one(email).await?;
+        if let Some(k) = existing {
+            return Ok(k.key);
+        }
+        let k = AuditKey { id: email.to_owned(), key: invite::new_token() };
+        let res = self
+            .db
+            .fluent()
+            .insert()
+            .into(&self.audit_key_coll)
+            .document_id(email)
+            .object(&k)
+            .execute::<()>()
+            .await;
+        match res {
+            Ok(_) => return Ok(k.key),
+            // created by a concurrent append
+            Err(errors::FirestoreError::DataConflictError(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let created: Option<AuditKey> =
+            self.db.fluent().select().by_id_in(&self.audit_key_coll).obj().one(email).await?;
+        created.map(|k| k.key).ok_or_else(|| format_err!("cannot create audit key"))
+    }
+
+    pub async fn get_audit_keys(&self) -> DBResult<BTreeMap<String, String>> {
+        let keys: Vec<AuditKey> =
+            self.db.fluent().select().from(self.audit_key_coll.as_str()).obj().query().await?;
+        Ok(keys.into_iter().map(|k| (k.id, k.key)).collect())
+    }
+
+    pub async fn delete_audit_key(&self, email: &str) -> DBResult<()> {
+        self.db.fluent().delete().from(&self.audit_key_coll).document_id(email).execute().await?;
+        Ok(())
+    }
+
+    pub async fn append_audit_entry(&self, entry: AuditEntry) -> DBResult<()> {
+        let mut keys = BTreeMap::new();
+        for email in audit::emails(&entry) {
+            let key = self.audit_key(&email).await?;
+            keys.insert(email, key);
+        }
+        let entry = audit::pseudonymize(entry, &keys);
+        // entries are inserted under their sequence number, so a concurrent append
+        // makes the insert fail and is retried on top of the new last entry
+        let mut attempts = 0;
+        loop {
+            let last: Vec<AuditEntry> = self
+                .db
+                .fluent()
+                .select()
+                .from(self.audit_coll.as_str())
+                .order_by([("seq", FirestoreQueryDirection::Descending)])
+                .limit(1)
+                .obj()
+                .query()
+                .await?;
+            let chained = audit::chain(last.first(), entry.clone())?;
+            let res = self
+                .db
+                .fluent()
+                .insert()
+                .into(&self.audit_coll)
+                .document_id(audit::entry_id(chained.seq))
+                .object(&chained)
+                .execute::<()>()
+                .await;
+            match res {
+                Ok(_) => return Ok(()),
+                Err(errors::FirestoreError::DataConflictError(_)) if attempts < 3 => attempts += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub async fn get_audit_entries(&self) -> DBResult<Vec<AuditEntry>> {
+        let entries: Vec<AuditEntry> = self
+            .db
+            .fluent()
+            .select()
+            .from(self.audit_coll.as_str())
+            .order_by([("seq", FirestoreQueryDirection::Ascending)])
+            .obj()
+            .query()
+            .await?;
+        Ok(entries)
     }
 
     pub async fn get_all_messages(&self) -> DBResult<BTreeMap<String, SecretMessage>> {
@@ -225,16 +620,56 @@ impl Storage {
         }
         Ok(res)
     }
+    pub async fn get_messages_by_role(
+        &self,
+        role: MessageRole,
+        email: &str,
+    ) -> DBResult<BTreeMap<String, SecretMessage>> {
+        let messages: Vec<SecretMessage> = self
+            .db
+            .fluent()
+            .select()
+            .from(self.message_coll.as_str())
+            .filter(|q| match role {
+                MessageRole::Owner => q.field(path!(SecretMessage::owner)).eq(email),
+                MessageRole::Recipient => q.field(path!(SecretMessage::recipient)).eq(email),
+                MessageRole::Contact => {
+                    q.field(path!(SecretMessage::trusted_contacts)).array_contains(email)
+                }
+            })
+            .obj()
+            .query()
+            .await?;
+        Ok(messages.into_iter().map(|m| (m.id.clone(), m)).collect())
+    }
+    pub async fn shares_message(&self, email: &str, other: &str) -> DBResult<bool> {
+        for role in [MessageRole::Owner, MessageRole::Recipient, MessageRole::Contact] {
+            let messages = self.get_messages_by_role(role, email).await?;
+            if messages.values().any(|m| m.involves(other)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
     pub async fn unsubscribe_user(&self, email: String) -> DBResult<()> {
         let user: User = self.get_user(&email).await?;
-        let new_user =
-            User { id: user.id.clone(), last_seen: user.last_seen, ..Default::default() };
+        let new_user = User {
+            subscription: Subscription::default(),
+            subscription_gone_on: 0,
+            resubscribe_requested_on: 0,
+            ..user.clone()
+        };
         self.put_user(new_user).await?;
         Ok(())
     }
     pub async fn subscribe_user(&self, email: String, sub: Subscription) -> DBResult<()> {
         let user: User = self.get_user(&email).await?;
-        let new_user = User { id: user.id.clone(), last_seen: user.last_seen, subscription: sub };
+        let new_user = User {
+            subscription: sub,
+            subscription_gone_on: 0,
+            resubscribe_requested_on: 0,
+            ..user.clone()
+        };
         self.put_user(new_user).await?;
         Ok(())
     }
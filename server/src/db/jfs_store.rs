@@ -1,22 +1,43 @@
 use std::{
     collections::BTreeMap,
-    fs::create_dir_all,
-    path::Path,
+    fs::{create_dir_all, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
 use jfs::Store;
-use log::info;
+use tracing::{debug, error, info};
 
-use super::DBResult;
-use crate::data_struct::{MessageWithLastSeen, SecretMessage, Subscription, User};
+use super::{DBError, DBResult};
+use crate::{
+    audit::{self, SYSTEM_ACTOR},
+    data_struct::{
+        AuditEntry, AuditEvent, AuditKey, ContactConfirmation, MessageRole, MessageWithLastSeen,
+        SecretMessage, Subscription, User,
+    },
+    invite,
+    telemetry::redact,
+};
 
 pub struct Storage {
     user_store: Store,
     message_store: Store,
+    audit_store: Store,
+    audit_key_store: Store,
+    /// Last audit entry, so that appends don't read the whole log.
+    audit_head_store: Store,
+    audit_lock_path: PathBuf,
+    db_path: PathBuf,
 }
 
+const AUDIT_HEAD: &str = "head";
+
+/// Serializes audit log appends within the process, the lock file does across
+/// processes such as the scheduler and the command line.
+static AUDIT_APPEND: Mutex<()> = Mutex::new(());
+
 impl Storage {
     pub fn new(id: &str) -> DBResult<Storage> {
         let cfg = jfs::Config { pretty: true, single: true, ..Default::default() };
@@ -24,29 +45,78 @@ impl Storage {
         create_dir_all(db_path)?;
         let u = Store::new_with_cfg(db_path.join("users").as_path(), cfg)?;
         let m = Store::new_with_cfg(db_path.join("messages").as_path(), cfg)?;
-        Ok(Storage { user_store: u, message_store: m })
+        // one file per entry, so that appending doesn't rewrite the log
+        let entry_cfg = jfs::Config { single: false, ..cfg };
+        let a = Store::new_with_cfg(db_path.join("audit").as_path(), entry_cfg)?;
+        let k = Store::new_with_cfg(db_path.join("audit_keys").as_path(), cfg)?;
+        let h = Store::new_with_cfg(db_path.join("audit_head").as_path(), cfg)?;
+        Ok(Storage {
+            user_store: u,
+            message_store: m,
+            audit_store: a,
+            audit_key_store: k,
+            audit_head_store: h,
+            audit_lock_path: db_path.join("audit.lock"),
+            db_path: db_path.to_path_buf(),
+        })
+    }
+    pub async fn check_connection(&self) -> DBResult<()> {
+        // only look at the directory, reading the stores would parse them
+        if std::fs::metadata(&self.db_path)?.permissions().readonly() {
+            return Err(anyhow!("storage directory is read-only"));
+        }
+        Ok(())
     }
     pub async fn put_user(&self, user: User) -> DBResult<()> {
         let id = self.user_store.save_with_id(&user, &user.id)?;
-        info!("user upserted, Id: {}", id);
+        debug!(user = %redact(&id), "user upserted");
         Ok(())
     }
     pub async fn get_user(&self, id: &str) -> DBResult<User> {
         let u = self.user_store.get::<User>(id)?;
         Ok(u)
     }
-    pub async fn put_message(&self, mut message: SecretMessage) -> DBResult<()> {
+    pub async fn get_all_users(&self) -> DBResult<Vec<User>> {
+        let res = self.user_store.all::<User>()?;
+        Ok(res.into_values().collect())
+    }
+    pub async fn delete_user(&self, id: &str) -> DBResult<()> {
+        self.user_store.delete(id)?;
+        Ok(())
+    }
+    pub async fn put_message(&self, mut message: SecretMessage) -> DBResult<String> {
         if message.owner.is_empty() {
-            return Err(anyhow!("owner must not be empty"));
+            return Err(DBError::invalid("owner must not be empty"));
         }
         if message.max_failed_verification < 1 || message.max_failed_verification > 9 {
-            return Err(anyhow!("maximum consecutive failure should be between 1 and 9"));
+            return Err(DBError::invalid("maximum consecutive failure should be between 1 and 9"));
         }
         if message.verify_every_minutes < 1 || message.verify_every_minutes > 4336204 {
-            return Err(anyhow!(
-                "maximum time between verification should be between 1 minute and 99 months"
+            return Err(DBError::invalid(
+                "maximum time between verification should be between 1 minute and 99 months",
+            ));
+        }
+        if message.claim_veto_minutes > 4336204 {
+            return Err(DBError::invalid("claim veto window should be less than 99 months"));
+        }
+        if message.title.chars().count() > 100 {
+            return Err(DBError::invalid("title should be at most 100 characters"));
+        }
+        if message.labels.len() > 10 || message.labels.iter().any(|l| l.chars().count() > 32) {
+            return Err(DBError::invalid(
+                "a message can have at most 10 labels of up to 32 characters",
             ));
         }
+        if message.note.chars().count() > 1000 {
+            return Err(DBError::invalid("note should be at most 1000 characters"));
+        }
+        let days = [message.expire_after_days, message.purge_after_reveal_days];
+        if days.iter().flatten().any(|d| *d > 36500) {
+            return Err(DBError::invalid("retention period should be at most 100 years"));
+        }
+        if message.trusted_contacts.len() > 9 {
+            return Err(DBError::invalid("a message can have at most 9 trusted contacts"));
+        }
         if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
             message.created_ts = now.as_secs();
         } else {
@@ -54,8 +124,8 @@ impl Storage {
         }
 
         let id = self.message_store.save(&message)?;
-        info!("message upserted, Id: {}", id);
-        Ok(())
+        debug!(message_id = %id, "message upserted");
+        Ok(id)
     }
     pub async fn update_message_notified_on(&self, id: &str, email: &str) -> DBResult<()> {
         let mut message: SecretMessage = self.message_store.get(id)?;
@@ -64,12 +134,114 @@ impl Storage {
                 message.recipient_notified_on = now.as_secs();
             } else if email == message.owner {
                 message.owner_notified_on = now.as_secs();
+            } else if message.trusted_contacts.iter().any(|c| c == email) {
+                message.contacts_notified_on = now.as_secs();
             }
             self.message_store.save_with_id(&message, id)?;
         }
         Ok(())
     }
 
+    pub async fn set_message_claimed_on(&self, id: &str, claimed_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.claimed_on = claimed_on;
+        message.claim_updated_on =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn set_message_vouched_on(&self, id: &str, vouched_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.vouched_on = vouched_on;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn set_message_incapacity_confirmed_on(
+        &self,
+        id: &str,
+        contact: &str,
+        confirmed_on: u64,
+    ) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.incapacity_confirmations.retain(|c| c.contact != contact);
+        message
+            .incapacity_confirmations
+            .push(ContactConfirmation { contact: contact.to_owned(), confirmed_on });
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn set_message_accepted_on(&self, id: &str, accepted_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.accepted_on = accepted_on;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn set_message_paused(&self, id: &str, paused: bool) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        if message.paused && !paused {
+            message.resumed_on = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        }
+        message.paused = paused;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn set_message_resumed_on(&self, id: &str, resumed_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.resumed_on = resumed_on;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn set_message_revealed_on(&self, id: &str, revealed_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.revealed_on = revealed_on;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn purge_system_share(&self, id: &str, purged_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.system_share = String::new();
+        message.share_purged_on = purged_on;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn remove_trusted_contact(&self, id: &str, email: &str) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.trusted_contacts.retain(|c| c != email);
+        message.incapacity_confirmations.retain(|c| c.contact != email);
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn activate_message(&self, id: &str) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.invite_token_hash = String::new();
+        message.recipient_left_on = 0;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    pub async fn detach_recipient(&self, id: &str, left_on: u64) -> DBResult<()> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.recipient_left_on = left_on;
+        self.message_store.save_with_id(&message, id)?;
+        Ok(())
+    }
+
+    async fn set_message_retrieved_on(&self, id: &str) -> DBResult<u64> {
+        let mut message: SecretMessage = self.message_store.get(id)?;
+        message.retrieved_on = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.message_store.save_with_id(&message, id)?;
+        Ok(message.retrieved_on)
+    }
+
     pub async fn set_message_revealed_if_needed(&self, id: &str) -> DBResult<bool> {
         let mut m = self.get_message(id).await?;
         if m.revealed {
@@ -78,15 +250,22 @@ impl Storage {
         // not revealed yet in db
         let owner = self.get_user(&m.owner).await?;
         if let Ok(r) = m.should_reveal(owner.last_seen) {
-            if r {
+            // a disabled owner's messages are paused
+            if r && !owner.disabled {
                 m.revealed = true;
+                m.revealed_on =
+                    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
                 self.message_store.save_with_id(&m, id)?;
+                let entry = AuditEntry::for_message(AuditEvent::MessageRevealed, SYSTEM_ACTOR, &m);
+                if let Err(e) = self.append_audit_entry(entry).await {
+                    error!(message_id = %id, "cannot record reveal in audit log: {}", e);
+                }
                 return Ok(true);
             }
         }
         Ok(false)
     }
-    async fn get_message(&self, id: &str) -> DBResult<SecretMessage> {
+    pub async fn get_message(&self, id: &str) -> DBResult<SecretMessage> {
         let message = self.message_store.get(id)?;
         Ok(message)
     }
@@ -98,40 +277,100 @@ impl Storage {
             .get_all_messages()
             .await?
             .into_iter()
-            .filter(|x| (x.1.owner == email || x.1.recipient == email))
+            .filter(|x| {
+                x.1.owner == email
+                    || x.1.recipient == email
+                    || x.1.trusted_contacts.contains(&email)
+            })
             .collect();
 
         let mut out: Vec<MessageWithLastSeen> = Vec::new();
         for (k, v) in messages {
-            let owner = self.get_user(&v.owner).await?;
-            let recipient = self.get_user(&v.recipient).await?;
-            let mut m = MessageWithLastSeen {
-                id: k.to_owned(),
-                created_ts: v.created_ts,
-                owner: owner.id,
-                recipient: recipient.id,
-                system_share: "".to_owned(),
-                verify_every_minutes: v.verify_every_minutes,
-                max_failed_verification: v.max_failed_verification,
-                owner_last_seen: owner.last_seen,
-                recipient_last_seen: recipient.last_seen,
-                revealed: v.revealed,
-            };
+            out.push(self.with_last_seen(&email, &k, v).await?);
+        }
+        Ok(out)
+    }
 
-            // first set revealed on db if needed, this flag should only change from false
-            // -> true once
-            m.revealed = self.set_message_revealed_if_needed(&k).await?;
+    pub async fn get_message_for_email(
+        &self,
+        email: String,
+        id: &str,
+    ) -> DBResult<MessageWithLastSeen> {
+        let v = self.get_message(id).await.map_err(|_| DBError::MessageNotFound)?;
+        if !v.involves(&email) {
+            return Err(DBError::MessageNotFound.into());
+        }
+        self.with_last_seen(&email, id, v).await
+    }
 
-            if email == v.owner {
-                m.system_share = v.system_share.clone();
-            }
-            // disclose system share to recipient if revealed is true
-            if email == v.recipient && m.revealed {
-                m.system_share = v.system_share.clone();
+    /// Adds the owner's and recipient's last seen times to the message, and
+    /// discloses what the user may see of it.
+    async fn with_last_seen(
+        &self,
+        email: &str,
+        k: &str,
+        v: SecretMessage,
+    ) -> DBResult<MessageWithLastSeen> {
+        let owner = self.get_user(&v.owner).await?;
+        // invited recipients may not be registered yet
+        let recipient_last_seen =
+            self.get_user(&v.recipient).await.map(|u| u.last_seen).unwrap_or_default();
+        let mut m = MessageWithLastSeen {
+            id: k.to_owned(),
+            created_ts: v.created_ts,
+            owner: owner.id,
+            recipient: v.recipient.clone(),
+            system_share: "".to_owned(),
+            verify_every_minutes: v.verify_every_minutes,
+            max_failed_verification: v.max_failed_verification,
+            owner_last_seen: owner.last_seen,
+            recipient_last_seen,
+            revealed: v.revealed,
+            claim_veto_minutes: v.claim_veto_minutes,
+            claimed_on: v.claimed_on,
+            trusted_contacts: v.trusted_contacts.clone(),
+            vouched_on: v.vouched_on,
+            incapacity_confirmations: v.incapacity_confirmations.clone(),
+            require_acceptance: v.require_acceptance,
+            accepted_on: v.accepted_on,
+            title: v.title.clone(),
+            labels: v.labels.clone(),
+            note: "".to_owned(),
+            revealed_on: v.revealed_on,
+            expire_after_days: v.expire_after_days,
+            purge_after_reveal_days: v.purge_after_reveal_days,
+            retrieved_on: v.retrieved_on,
+            share_purged_on: v.share_purged_on,
+            pending_invite: v.is_pending_invite(),
+            paused: v.paused,
+        };
+
+        // first set revealed on db if needed, this flag should only change from false
+        // -> true once
+        m.revealed = self.set_message_revealed_if_needed(k).await?;
+
+        if email == v.owner {
+            m.system_share = v.system_share.clone();
+            m.note = v.note.clone();
+        }
+        // disclose system share and note to recipient if revealed is true
+        if email == v.recipient && m.revealed {
+            m.system_share = v.system_share.clone();
+            m.note = v.note.clone();
+            if v.retrieved_on == 0 {
+                m.retrieved_on = self.set_message_retrieved_on(k).await?;
+                info!(
+                    message_id = %k,
+                    event = "system_share_disclosed",
+                    "system share retrieved by recipient",
+                );
+                let entry = AuditEntry::for_message(AuditEvent::SystemShareDisclosed, email, &v);
+                if let Err(e) = self.append_audit_entry(entry).await {
+                    error!(message_id = %k, "cannot record disclosure in audit log: {}", e);
+                }
             }
-            out.push(m);
         }
-        Ok(out)
+        Ok(m)
     }
 
     pub async fn delete_message_from_email(
@@ -143,7 +382,7 @@ impl Storage {
             .get_all_messages()
             .await?
             .into_iter()
-            .filter(|x| (x.1.owner == email || x.1.recipient == email))
+            .filter(|x| x.1.owner == email || x.1.recipient == email)
             .collect();
 
         if messages.contains_key(message_id.as_str()) {
@@ -158,23 +397,100 @@ impl Storage {
                 return Ok(());
             }
         }
-        Err(anyhow!("message not found"))
+        Err(DBError::MessageNotFound.into())
+    }
+    pub async fn delete_message(&self, id: &str) -> DBResult<()> {
+        self.message_store.delete(id)?;
+        Ok(())
+    }
+    async fn audit_key(&self, email: &str) -> DBResult<String> {
+        if let Ok(k) = self.audit_key_store.get::<AuditKey>(email) {
+            return Ok(k.key);
+        }
+        let k = AuditKey { id: email.to_owned(), key: invite::new_token() };
+        self.audit_key_store.save_with_id(&k, email)?;
+        Ok(k.key)
+    }
+    pub async fn get_audit_keys(&self) -> DBResult<BTreeMap<String, String>> {
+        let keys = self.audit_key_store.all::<AuditKey>()?;
+        Ok(keys.into_values().map(|k| (k.id, k.key)).collect())
+    }
+    pub async fn delete_audit_key(&self, email: &str) -> DBResult<()> {
+        if self.audit_key_store.get::<AuditKey>(email).is_ok() {
+            self.audit_key_store.delete(email)?;
+        }
+        Ok(())
+    }
+    pub async fn append_audit_entry(&self, entry: AuditEntry) -> DBResult<()> {
+        let mut keys = BTreeMap::new();
+        for email in audit::emails(&entry) {
+            let key = self.audit_key(&email).await?;
+            keys.insert(email, key);
+        }
+        let entry = audit::pseudonymize(entry, &keys);
+
+        let _guard = AUDIT_APPEND.lock().unwrap_or_else(PoisonError::into_inner);
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.audit_lock_path)?;
+        lock.lock()?;
+        let mut last = self.audit_head_store.get::<AuditEntry>(AUDIT_HEAD).ok();
+        // an append may have stopped before moving the head
+        loop {
+            let next = last.as_ref().map_or(0, |l| l.seq + 1);
+            match self.audit_store.get::<AuditEntry>(&audit::entry_id(next)) {
+                Ok(e) => last = Some(e),
+                Err(_) => break,
+            }
+        }
+        let entry = audit::chain(last.as_ref(), entry)?;
+        self.audit_store.save_with_id(&entry, &audit::entry_id(entry.seq))?;
+        self.audit_head_store.save_with_id(&entry, AUDIT_HEAD)?;
+        Ok(())
+    }
+    pub async fn get_audit_entries(&self) -> DBResult<Vec<AuditEntry>> {
+        let res = self.audit_store.all::<AuditEntry>()?;
+        Ok(res.into_values().collect())
     }
     pub async fn get_all_messages(&self) -> DBResult<BTreeMap<String, SecretMessage>> {
         let res = self.message_store.all::<SecretMessage>()?;
         Ok(res)
     }
+    pub async fn get_messages_by_role(
+        &self,
+        role: MessageRole,
+        email: &str,
+    ) -> DBResult<BTreeMap<String, SecretMessage>> {
+        let messages = self.get_all_messages().await?;
+        Ok(messages.into_iter().filter(|(_, m)| m.has_role(role, email)).collect())
+    }
+    pub async fn shares_message(&self, email: &str, other: &str) -> DBResult<bool> {
+        // messages are a single file, which has to be read whole anyway
+        let messages = self.get_all_messages().await?;
+        Ok(messages.values().any(|m| m.involves(email) && m.involves(other)))
+    }
     pub async fn unsubscribe_user(&self, email: String) -> DBResult<()> {
         let user: User = self.user_store.get(email.as_str())?;
-        let new_user =
-            User { id: user.id.clone(), last_seen: user.last_seen, ..Default::default() };
+        let new_user = User {
+            subscription: Subscription::default(),
+            subscription_gone_on: 0,
+            resubscribe_requested_on: 0,
+            ..user.clone()
+        };
         self.user_store.delete(&user.id)?;
         self.put_user(new_user).await?;
         Ok(())
     }
     pub async fn subscribe_user(&self, email: String, sub: Subscription) -> DBResult<()> {
         let user: User = self.user_store.get(email.as_str())?;
-        let new_user = User { id: user.id.clone(), last_seen: user.last_seen, subscription: sub };
+        let new_user = User {
+            subscription: sub,
+            subscription_gone_on: 0,
+            resubscribe_requested_on: 0,
+            ..user.clone()
+        };
         self.user_store.delete(&user.id)?;
         self.put_user(new_user).await?;
         Ok(())
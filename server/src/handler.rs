@@ -1,4 +1,6 @@
 use std::{
+    future::Future,
+    pin::Pin,
     sync::{
         atomic::{
             AtomicBool,
@@ -10,139 +12,367 @@ use std::{
 };
 
 use actix_http::Response;
-use log::{debug, info};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument};
+use utoipa::ToSchema;
 
+mod account;
+mod admin;
 mod gsi;
+mod health;
 mod http_error;
+mod openapi;
 
 use actix_web::{
-    delete,
-    error::{ErrorForbidden, ErrorNotImplemented, ErrorTooManyRequests, ErrorUnauthorized},
-    get, post, web, Responder, Result,
+    dev::Payload,
+    error::InternalError,
+    http::header::WWW_AUTHENTICATE,
+    middleware::DefaultHeaders,
+    web::{self, ServiceConfig},
+    FromRequest, HttpRequest, HttpResponse, Responder, ResponseError, Result,
 };
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+pub use admin::{message_summaries, user_summaries, MessageSummary, UserSummary};
 use http_error::IntoHttpError;
+pub use http_error::{ApiError, ErrorCode};
 
 use crate::{
-    data_struct::{SecretMessage, Subscription, User},
-    db,
+    audit,
+    data_struct::{
+        AuditEntry, AuditEvent, MessageRole, MessageWithLastSeen, RetentionPolicy, SecretMessage,
+        Subscription, User, UserID,
+    },
+    db, invite, metrics,
     notifier::{self, AppPushMessage},
+    ratelimit::{self, Limit, RateLimiter},
+    registration::{AdminRole, Admission, Identity, RegistrationPolicy},
+    telemetry::redact,
 };
 
 pub struct AppState {
     pub db: db::DB,
     pub web_push: Arc<notifier::WebPusher>,
-    pub block_registration: bool,
+    pub registration: RegistrationPolicy,
+    pub admins: AdminRole,
+    pub inviter: invite::Inviter,
     pub scheduled_task_period: u64,
     pub oauth_client_id: String,
     pub serverless_token: String,
+    pub metrics_token: String,
     pub scheduled_task_running: AtomicBool,
+    pub retention: RetentionPolicy,
+    pub rate_limiter: web::Data<RateLimiter>,
 }
 
 async fn authorize_user(access_token: &str, data: &web::Data<AppState>) -> Result<String> {
-    // NOTE: auth user is expected to return httpResponse error on failure
-    // http_error currently sets the status code correctly but ignores the message
-    // part
+    authorize_user_in(access_token, data, ratelimit::API).await
+}
+
+/// Signs the user in and counts the request against their limit in the bucket.
+async fn authorize_user_in(
+    access_token: &str,
+    data: &web::Data<AppState>,
+    bucket: &'static str,
+) -> Result<String> {
+    let email = sign_in(access_token, data, None).await?.email;
+    limit_user(data, bucket, &email).await?;
+    Ok(email)
+}
+
+/// Counts the request against the verified user's limit in the bucket, on top
+/// of the per-address limit applied before authentication.
+async fn limit_user(data: &AppState, bucket: &'static str, email: &str) -> Result<()> {
+    if let Some(retry_after) = data.rate_limiter.check_user(bucket, email).await {
+        metrics::RATE_LIMITED.with_label_values(&[bucket]).inc();
+        let res = ratelimit::too_many_requests(retry_after);
+        return Err(InternalError::from_response("too many requests", res).into());
+    }
+    Ok(())
+}
 
-    let email = gsi::get_email_from_token(access_token, &data.oauth_client_id)
+/// Returns the token's identity without checking the user's registration, for
+/// the few endpoints that disabled or pending users can still use.
+async fn verify_identity(access_token: &str, data: &web::Data<AppState>) -> Result<Identity> {
+    let identity = gsi::verify_token(access_token, &data.oauth_client_id)
         .await
-        .http_unauthorized_error("cannot get valid email from token")?;
-
-    debug!("authorizing {}", email);
-    let res =
-        data.db.get_user(email.as_str()).await.http_unauthorized_error("email is not registered");
-    info!("done get {}", email);
-
-    // exit if user doesn't exist and new registration isn't allowed
-    if data.block_registration {
-        if let Err(e) = res {
-            debug!(
-                "rejecting auth request from {}, email is not registered and registration disabled",
-                email
-            );
-            return Err(e);
+        .http_error(ErrorCode::InvalidToken, "cannot get valid email from token")?;
+    Ok(identity)
+}
+
+/// Returns the token's user, registering them first if the registration
+/// policy admits them.
+#[instrument(skip_all)]
+async fn sign_in(
+    access_token: &str,
+    data: &web::Data<AppState>,
+    invite_code: Option<&str>,
+) -> Result<Identity> {
+    let identity = verify_identity(access_token, data).await?;
+    let email = identity.email.clone();
+    let admin = data.admins.includes(&identity);
+
+    debug!(user = %redact(&email), "authorizing user");
+    let res = data.db.get_user(email.as_str()).await;
+    debug!(user = %redact(&email), registered = res.is_ok(), "user looked up");
+
+    let invited = match invite_code {
+        Some(code) => {
+            data.registration.is_invite_code(code)
+                || (data.registration.admit_invited_recipients
+                    && invite::is_invited(&data.db, &email, code).await)
         }
+        None => false,
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let user = match res {
+        // the policy may have changed since a pending user registered
+        Ok(user) if user.pending_approval => {
+            if data.registration.admit(&email, invited, admin) != Admission::Approved {
+                metrics::auth_failure("registration_pending");
+                return Err(registration_pending().into());
+            }
+            audit::record(&data.db, AuditEntry::new(AuditEvent::UserApproved, &email)).await;
+            User { pending_approval: false, ..user }
+        }
+        Ok(user) => user,
+        Err(_) => {
+            let admission = data.registration.admit(&email, invited, admin);
+            if admission == Admission::Rejected {
+                debug!(user = %redact(&email), "rejecting unregistered user");
+                metrics::auth_failure("registration_blocked");
+                return Err(
+                    ApiError::new(ErrorCode::UserNotRegistered, "email is not registered").into()
+                );
+            }
+            let pending = admission == Admission::Pending;
+            let user = User {
+                id: email.clone(),
+                last_seen: now,
+                pending_approval: pending,
+                ..Default::default()
+            };
+            data.db.put_user(user).await.map_err(ApiError::from)?;
+            info!(user = %redact(&email), pending, event = "user_registered", "user registered");
+            let entry = AuditEntry::new(AuditEvent::UserRegistered, &email)
+                .with_details(if pending { "pending_approval" } else { "" });
+            audit::record(&data.db, entry).await;
+            if pending {
+                return Err(registration_pending().into());
+            }
+            return Ok(identity);
+        }
+    };
+    if user.disabled {
+        debug!(user = %redact(&email), "rejecting disabled user");
+        metrics::auth_failure("user_disabled");
+        return Err(ApiError::new(ErrorCode::UserDisabled, "account is disabled").into());
     }
 
     // update last seen
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
-    let sub = if let Ok(user) = res { user.subscription } else { Subscription::default() };
-
     data.db
-        .put_user(User { id: email.clone(), last_seen: now, subscription: sub })
+        .put_user(User { last_seen: now, ..user })
         .await
-        .http_internal_error(&format!("cannot update last_seen for user {}", email))?;
+        .inspect_err(|e| error!(user = %redact(&email), "cannot update last_seen: {}", e))
+        .http_internal_error("cannot update last_seen")?;
 
-    info!("{} authorized and updated", email);
-    Ok(email)
+    debug!(user = %redact(&email), "user authorized");
+    Ok(identity)
+}
+
+fn registration_pending() -> ApiError {
+    ApiError::new(ErrorCode::RegistrationPending, "registration is waiting for admin approval")
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+struct RegistrationRequest {
+    /// Lets users outside the allowed domains and emails register.
+    #[serde(default)]
+    invite_code: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/register",
+    tag = "user",
+    request_body = RegistrationRequest,
+    responses(
+        (status = 200, description = "Registered, or already registered"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn register_user(
+    data: web::Data<AppState>,
+    auth: Bearer,
+    req: web::Json<RegistrationRequest>,
+) -> Result<impl Responder> {
+    let invite_code = Some(req.invite_code.as_str()).filter(|c| !c.is_empty());
+    let identity = sign_in(auth.token(), &data, invite_code).await?;
+    limit_user(&data, ratelimit::API, &identity.email).await?;
+    Ok(Response::ok())
 }
 
-#[get("/serverless-task")]
+#[utoipa::path(
+    post,
+    path = "/api/v1/scheduled-task",
+    tag = "tasks",
+    responses(
+        (status = 200, description = "Task executed"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
 async fn serverless_scheduled_task(
     data: web::Data<AppState>,
-    auth: BearerAuth,
+    auth: Bearer,
 ) -> Result<impl Responder> {
     if data.serverless_token.is_empty() {
-        return Err(ErrorNotImplemented("this feature is not active\n"));
+        return Err(ApiError::new(ErrorCode::FeatureDisabled, "this feature is not active").into());
     }
     if auth.token() != data.serverless_token {
-        return Err(ErrorUnauthorized("correct access token required\n"));
+        return Err(ApiError::new(ErrorCode::Unauthorized, "correct access token required").into());
     }
     if data.scheduled_task_running.load(std::sync::atomic::Ordering::Acquire) {
-        return Err(ErrorTooManyRequests("task is still executing\n"));
+        return Err(ApiError::new(ErrorCode::TaskRunning, "task is still executing").into());
     }
     if data.scheduled_task_running.compare_exchange(false, true, SeqCst, Acquire).is_err() {
-        return Err(ErrorTooManyRequests("task is still executing\n"));
+        return Err(ApiError::new(ErrorCode::TaskRunning, "task is still executing").into());
     }
-    notifier::execute_tasks(&data.db, &data.web_push)
-        .await
-        .http_internal_error("error executing scheduled task")?;
+    let res = notifier::execute_tasks(&data.db, &data.web_push, &data.retention).await;
+    data.scheduled_task_running.store(false, SeqCst);
+    res.http_internal_error("error executing scheduled task")?;
     Ok("task executed successfully\n")
 }
 
-#[get("/message-list")]
-async fn message_list(data: web::Data<AppState>, auth: BearerAuth) -> Result<impl Responder> {
+#[utoipa::path(
+    get,
+    path = "/api/v1/messages",
+    tag = "messages",
+    responses(
+        (status = 200, body = Vec<MessageWithLastSeen>),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_list(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
     let email = authorize_user(auth.token(), &data).await?;
-    info!("getting message for {}", email);
-    let messages = data
-        .db
-        .get_messages_for_email(email)
-        .await
-        .map_err(|e| string_error::into_err(e.to_string()))?;
+    debug!(user = %redact(&email), "listing messages");
+    let messages = data.db.get_messages_for_email(email).await.map_err(ApiError::from)?;
     Ok(web::Json(messages))
 }
 
-#[get("/user-pong")]
-async fn user_pong(data: web::Data<AppState>, auth: BearerAuth) -> Result<impl Responder> {
+#[utoipa::path(
+    get,
+    path = "/api/v1/messages/{id}",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, body = MessageWithLastSeen),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_get(
+    data: web::Data<AppState>,
+    auth: Bearer,
+    id: web::Path<String>,
+) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let message = data.db.get_message_for_email(email, &id).await.map_err(ApiError::from)?;
+    Ok(web::Json(message))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/check-in",
+    tag = "user",
+    responses(
+        (status = 200, description = "Check-in recorded"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn user_pong(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
     let email = authorize_user(auth.token(), &data).await?;
-    info!("received pong from {}", email);
+    info!(user = %redact(&email), event = "check_in", "owner checked in");
+    let entry = AuditEntry { owner: email.clone(), ..AuditEntry::new(AuditEvent::CheckIn, &email) };
+    audit::record(&data.db, entry).await;
     Ok(Response::ok())
 }
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, ToSchema)]
 struct TestNotificationRequest {
     #[serde(default)]
     recipient: String,
 }
 
-#[post("/test-notification")]
+#[utoipa::path(
+    post,
+    path = "/api/v1/notifications/test",
+    tag = "notifications",
+    request_body = TestNotificationRequest,
+    responses(
+        (status = 200, description = "Notification sent"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
 async fn test_notification(
     data: web::Data<AppState>,
     notif_request: web::Json<TestNotificationRequest>,
-    auth: BearerAuth,
+    auth: Bearer,
 ) -> Result<impl Responder> {
-    let email = authorize_user(auth.token(), &data).await?;
+    let email = authorize_user_in(auth.token(), &data, ratelimit::NOTIFICATIONS).await?;
     let recipient_email = if !notif_request.recipient.is_empty() {
         notif_request.recipient.as_str()
     } else {
         email.as_str()
     };
-    let user = data
-        .db
-        .get_user(recipient_email)
-        .await
-        .map_err(|e| string_error::into_err(e.to_string()))?;
+    let (user, pair) = if recipient_email == email {
+        let user = data
+            .db
+            .get_user(recipient_email)
+            .await
+            .http_error(ErrorCode::UserNotFound, "user is not registered")?;
+        (user, None)
+    } else {
+        // unregistered and non-consenting recipients get the same answer, so
+        // that this can't be used to find out who is registered
+        let user = match data.db.get_user(recipient_email).await {
+            Ok(user) if user.allow_test_notifications => Some(user),
+            Ok(user) => {
+                let shared =
+                    data.db.shares_message(&email, &user.id).await.map_err(ApiError::from)?;
+                shared.then_some(user)
+            }
+            Err(_) => None,
+        };
+        let Some(user) = user else {
+            refuse_test_notification(&data, &email, recipient_email, "not_counterpart").await;
+            return Err(ApiError::new(
+                ErrorCode::RecipientNotAllowed,
+                "recipient doesn't share a message with you",
+            )
+            .into());
+        };
+
+        let pair = format!("{}:{}", email, user.id);
+        let cooldown = data.rate_limiter.check_key(ratelimit::NOTIFICATION_PAIR, &pair).await;
+        if let Some(retry_after) = cooldown {
+            metrics::RATE_LIMITED.with_label_values(&[ratelimit::NOTIFICATION_PAIR]).inc();
+            refuse_test_notification(&data, &email, &user.id, "cooldown").await;
+            return Err(ApiError::new(ErrorCode::RateLimited, "too many requests")
+                .with_details(format!("retry in {} seconds", retry_after))
+                .into());
+        }
+        (user, Some(pair))
+    };
 
     let mut push_message = AppPushMessage { tag: "test".to_string(), ..Default::default() };
     if user.id == email {
@@ -153,100 +383,932 @@ async fn test_notification(
         push_message.title = email.clone() + " says 👋";
         push_message.message = email.clone() + " wants to confirm that you're active on Ywinby";
     }
-    data.web_push
-        .send_message(user.subscription, push_message)
-        .await
-        .http_internal_error("cannot send push message")?;
-    info!("push notification test message sent to {}", user.id);
+    let sent = data.web_push.send_message(user.subscription, push_message).await;
+    if sent.is_err() {
+        // only delivered notifications count towards the cooldown
+        if let Some(pair) = &pair {
+            data.rate_limiter.refund_key(ratelimit::NOTIFICATION_PAIR, pair).await;
+        }
+    }
+    sent.http_error(ErrorCode::NotificationFailed, "cannot send push message")?;
+    info!(
+        user = %redact(&email),
+        recipient = %redact(&user.id),
+        event = "notification_sent",
+        "test notification sent",
+    );
+    let entry = AuditEntry {
+        owner: email.clone(),
+        recipient: user.id.clone(),
+        ..AuditEntry::new(AuditEvent::NotificationSent, &email).with_details("test")
+    };
+    audit::record(&data.db, entry).await;
     Ok(Response::ok())
 }
-#[post("/unsubscribe-user")]
-async fn unsubscribe_user(data: web::Data<AppState>, auth: BearerAuth) -> Result<impl Responder> {
+
+async fn refuse_test_notification(
+    data: &web::Data<AppState>,
+    email: &str,
+    recipient: &str,
+    reason: &str,
+) {
+    info!(
+        user = %redact(email),
+        recipient = %redact(recipient),
+        reason,
+        event = "notification_refused",
+        "test notification refused",
+    );
+    let entry = AuditEntry {
+        owner: email.to_owned(),
+        recipient: recipient.to_owned(),
+        ..AuditEntry::new(AuditEvent::NotificationRefused, email).with_details(reason)
+    };
+    audit::record(&data.db, entry).await;
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UserPreferences {
+    /// Accept test notifications from users who don't share a message with you.
+    allow_test_notifications: bool,
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/preferences",
+    tag = "user",
+    request_body = UserPreferences,
+    responses(
+        (status = 200, description = "Preferences updated"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn update_preferences(
+    data: web::Data<AppState>,
+    auth: Bearer,
+    req: web::Json<UserPreferences>,
+) -> Result<impl Responder> {
     let email = authorize_user(auth.token(), &data).await?;
+    let user = data.db.get_user(&email).await.map_err(ApiError::from)?;
     data.db
-        .unsubscribe_user(email.to_owned())
+        .put_user(User { allow_test_notifications: req.allow_test_notifications, ..user })
         .await
-        .map_err(|e| string_error::into_err(e.to_string()))?;
-    info!("{} unsubscribed", email);
+        .map_err(ApiError::from)?;
+    info!(user = %redact(&email), event = "preferences_updated", "user preferences updated");
     Ok(Response::ok())
 }
 
-#[derive(Deserialize)]
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/subscription",
+    tag = "user",
+    responses(
+        (status = 200, description = "Unsubscribed"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn unsubscribe_user(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    data.db.unsubscribe_user(email.to_owned()).await.map_err(ApiError::from)?;
+    info!(user = %redact(&email), event = "unsubscribed", "user unsubscribed");
+    Ok(Response::ok())
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct SubscriptionRequest {
     subscription: Subscription,
+    /// Public key the subscription was created with, from runtime-config.json.
+    #[serde(default)]
+    push_pubkey: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SubscriptionStatus {
+    subscribed: bool,
+    /// The subscription was created with a previous push key, and should be
+    /// replaced by one for the current key.
+    resubscribe: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/subscription",
+    tag = "user",
+    responses(
+        (status = 200, body = SubscriptionStatus),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn subscription_status(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let user = data.db.get_user(&email).await.map_err(ApiError::from)?;
+    let subscribed = !user.subscription.keys.auth.is_empty();
+    let resubscribe = subscribed && !data.web_push.is_current(&user.subscription);
+    Ok(web::Json(SubscriptionStatus { subscribed, resubscribe }))
 }
 
-#[post("/subscribe-user")]
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/subscription",
+    tag = "user",
+    request_body = SubscriptionRequest,
+    responses(
+        (status = 200, description = "Subscribed"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
 async fn subscribe_user(
     data: web::Data<AppState>,
-    auth: BearerAuth,
+    auth: Bearer,
     req: web::Json<SubscriptionRequest>,
 ) -> Result<impl Responder> {
     let email = authorize_user(auth.token(), &data).await?;
-    data.db
-        .subscribe_user(email.clone(), req.subscription.clone())
-        .await
-        .map_err(|e| string_error::into_err(e.to_string()))?;
-    info!("{} subscribed", email);
+    let pubkey = notifier::normalize_pubkey(&req.push_pubkey);
+    if !pubkey.is_empty() && !data.web_push.is_known_key(&pubkey) {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "push key is no longer in use")
+            .with_details("reload the app to subscribe with the current key")
+            .into());
+    }
+    let subscription = Subscription { vapid_pubkey: pubkey, ..req.subscription.clone() };
+    data.db.subscribe_user(email.clone(), subscription.clone()).await.map_err(ApiError::from)?;
+    info!(user = %redact(&email), event = "subscribed", "user subscribed");
+    activate_invited_messages(&data, &email, &subscription).await;
     Ok(Response::ok())
 }
 
-#[derive(Deserialize)]
-struct DeleteMessage {
-    message_id: String,
-}
-
-#[delete("/message")]
+#[utoipa::path(
+    delete,
+    path = "/api/v1/messages/{id}",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Message deleted"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
 async fn message_delete(
     data: web::Data<AppState>,
-    del_msg: web::Json<DeleteMessage>,
-    auth: BearerAuth,
+    message_id: MessageId,
+    auth: Bearer,
 ) -> Result<impl Responder> {
     let email = authorize_user(auth.token(), &data).await?;
+    let deleted = data.db.get_message(&message_id.0).await;
     data.db
-        .delete_message_from_email(email.to_owned(), del_msg.message_id.clone())
+        .delete_message_from_email(email.to_owned(), message_id.0.clone())
         .await
-        .map_err(|e| string_error::into_err(e.to_string()))?;
-    info!("{} deleted message {}", email, del_msg.message_id);
+        .map_err(ApiError::from)?;
+    info!(
+        user = %redact(&email),
+        message_id = %message_id.0,
+        event = "message_deleted",
+        "message deleted",
+    );
+    if let Ok(m) = deleted {
+        audit::record(&data.db, AuditEntry::for_message(AuditEvent::MessageDeleted, &email, &m))
+            .await;
+    }
     Ok(Response::ok())
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct NewMessage {
-    message: SecretMessage,
+    message: MessageDraft,
+}
+
+/// What an owner chooses when creating a message, the rest of its state is
+/// kept by the server.
+#[derive(Deserialize, ToSchema)]
+struct MessageDraft {
+    recipient: UserID,
+    system_share: String,
+    verify_every_minutes: u64,
+    max_failed_verification: u64,
+    /// Time given to the owner to object to a recipient's claim, 0 means the
+    /// recipient cannot file a claim on this message.
+    #[serde(default)]
+    claim_veto_minutes: u64,
+    #[serde(default)]
+    trusted_contacts: Vec<UserID>,
+    #[serde(default)]
+    require_acceptance: bool,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(default)]
+    note: String,
+    #[serde(default)]
+    expire_after_days: Option<u64>,
+    #[serde(default)]
+    purge_after_reveal_days: Option<u64>,
+}
+
+impl MessageDraft {
+    fn into_message(self, owner: &str) -> SecretMessage {
+        SecretMessage {
+            recipient: self.recipient,
+            system_share: self.system_share,
+            verify_every_minutes: self.verify_every_minutes,
+            max_failed_verification: self.max_failed_verification,
+            owner: owner.to_owned(),
+            claim_veto_minutes: self.claim_veto_minutes,
+            trusted_contacts: self.trusted_contacts,
+            require_acceptance: self.require_acceptance,
+            title: self.title,
+            labels: self.labels,
+            note: self.note,
+            expire_after_days: self.expire_after_days,
+            purge_after_reveal_days: self.purge_after_reveal_days,
+            ..Default::default()
+        }
+    }
 }
-#[post("/message")]
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages",
+    tag = "messages",
+    request_body = NewMessage,
+    responses(
+        (status = 200, description = "Message created"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
 async fn message_create(
     data: web::Data<AppState>,
-    auth: BearerAuth,
+    auth: Bearer,
     new_message: web::Json<NewMessage>,
 ) -> Result<impl Responder> {
     let email = authorize_user(auth.token(), &data).await?;
-    let mut m: SecretMessage = new_message.into_inner().message;
-    info!(
+    let mut m = new_message.into_inner().message.into_message(&email);
+    debug!(
         "scheduled_task_minute: {} every_minute: {}",
         data.scheduled_task_period / 60,
         m.verify_every_minutes
     );
     if data.scheduled_task_period > m.verify_every_minutes * 60 {
-        return Err(ErrorForbidden(format!(
-            "verification time is too short, server minimum is {:.0} minutes",
-            data.scheduled_task_period / 60
-        )));
+        return Err(ApiError::new(
+            ErrorCode::VerificationTooShort,
+            format!(
+                "verification time is too short, server minimum is {:.0} minutes",
+                data.scheduled_task_period / 60
+            ),
+        )
+        .into());
+    }
+    if m.recipient == email {
+        return Err(ApiError::new(
+            ErrorCode::OwnerIsRecipient,
+            "owner and recipient must be different",
+        )
+        .into());
+    }
+    // recipients who can't be notified yet are sent an invite instead, if
+    // there's a channel for it
+    let recipient = data.db.get_user(m.recipient.as_str()).await.ok();
+    let subscribed = recipient.as_ref().is_some_and(|r| !r.subscription.keys.auth.is_empty());
+    let invite_token = match (&recipient, subscribed) {
+        (_, true) => None,
+        (_, false) if data.inviter.is_enabled() => Some(invite::new_token()),
+        (None, false) => {
+            return Err(ApiError::new(
+                ErrorCode::RecipientNotRegistered,
+                "recipient email is not registered",
+            )
+            .into())
+        }
+        (Some(_), false) => {
+            return Err(ApiError::new(
+                ErrorCode::RecipientNotSubscribed,
+                "recipient hasn't subscribe to push notification",
+            )
+            .into())
+        }
+    };
+    if invite_token.is_some() {
+        // invites send email or webhooks to arbitrary addresses, so owners
+        // only get a few a day, however many messages they delete since
+        let cooldown = data.rate_limiter.check_key(ratelimit::INVITES, &email).await;
+        if let Some(retry_after) = cooldown {
+            metrics::RATE_LIMITED.with_label_values(&[ratelimit::INVITES]).inc();
+            return Err(ApiError::new(ErrorCode::RateLimited, "too many invites sent")
+                .with_details(format!("retry in {} seconds", retry_after))
+                .into());
+        }
+    }
+    m.invite_token_hash = invite_token.as_deref().map(invite::token_hash).unwrap_or_default();
+    for c in m.trusted_contacts.iter() {
+        if *c == email || *c == m.recipient {
+            return Err(ApiError::new(
+                ErrorCode::InvalidTrustedContact,
+                "trusted contacts must differ from owner and recipient",
+            )
+            .into());
+        }
+        let contact = data.db.get_user(c).await.http_error(
+            ErrorCode::TrustedContactNotRegistered,
+            "trusted contact is not registered",
+        )?;
+        if contact.subscription.keys.auth.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::TrustedContactNotSubscribed,
+                "trusted contact hasn't subscribe to push notification",
+            )
+            .into());
+        }
+    }
+    let mut entry = AuditEntry::for_message(AuditEvent::MessageCreated, &email, &m);
+    entry.message_id = data.db.put_message(m.clone()).await.map_err(ApiError::from)?;
+    m.id = entry.message_id.clone();
+
+    if let Some(token) = invite_token {
+        // a message nobody can be invited to would never activate
+        if let Err(e) = data.inviter.send(&email, &m.recipient, &token).await {
+            error!(
+                user = %redact(&m.recipient),
+                message_id = %m.id,
+                "cannot send invite: {:?}",
+                e
+            );
+            // only invites that went out count towards the limit
+            data.rate_limiter.refund_key(ratelimit::INVITES, &email).await;
+            data.db.delete_message(&m.id).await.map_err(ApiError::from)?;
+            return Err(ApiError::new(
+                ErrorCode::NotificationFailed,
+                "cannot send invite to recipient",
+            )
+            .into());
+        }
+    }
+    info!(
+        user = %redact(&email),
+        message_id = %m.id,
+        pending_invite = m.is_pending_invite(),
+        event = "message_created",
+        "message created",
+    );
+    audit::record(&data.db, entry.clone()).await;
+
+    match recipient {
+        Some(recipient) if !m.is_pending_invite() => {
+            request_acceptance(&data, &m, recipient.subscription).await
+        }
+        _ => {
+            let entry = AuditEntry { event: AuditEvent::InviteSent, ..entry };
+            audit::record(&data.db, entry).await;
+        }
+    }
+    Ok(Response::ok())
+}
+
+/// Asks the recipient to accept the message, if the owner requires it.
+async fn request_acceptance(data: &AppState, m: &SecretMessage, subscription: Subscription) {
+    if !m.require_acceptance {
+        return;
+    }
+    let push_message = AppPushMessage {
+        tag: "invitation".to_string(),
+        title: "New secret message".to_string(),
+        message: m.owner.clone()
+            + " has registered a secret message for you. Please accept it once you've stored \
+               your share.",
+    };
+    match data.web_push.send_message(subscription, push_message).await {
+        Ok(_) => {
+            let entry = AuditEntry::for_message(AuditEvent::NotificationSent, &m.owner, m);
+            audit::record(&data.db, entry.with_details("invitation")).await;
+        }
+        Err(e) => error!(
+            user = %redact(&m.recipient),
+            message_id = %m.id,
+            "cannot send invitation: {}",
+            e
+        ),
+    }
+}
+
+/// Activates the messages that were waiting for the user to subscribe.
+async fn activate_invited_messages(data: &AppState, email: &str, subscription: &Subscription) {
+    let messages = match data.db.get_messages_by_role(MessageRole::Recipient, email).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!(user = %redact(email), "cannot look up invited messages: {}", e);
+            return;
+        }
+    };
+    for (id, m) in messages.into_iter().filter(|(_, m)| m.is_pending_invite()) {
+        if let Err(e) = data.db.activate_message(&id).await {
+            error!(message_id = %id, "cannot activate message: {}", e);
+            continue;
+        }
+        info!(message_id = %id, event = "message_activated", "invited message activated");
+        let m = SecretMessage { id, ..m };
+        audit::record(&data.db, AuditEntry::for_message(AuditEvent::MessageActivated, email, &m))
+            .await;
+        request_acceptance(data, &m, subscription.clone()).await;
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages/{id}/accept",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Message accepted"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_accept(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let m = data
+        .db
+        .get_message(&message_id.0)
+        .await
+        .http_error(ErrorCode::MessageNotFound, "message not found")?;
+    if m.recipient != email {
+        return Err(ApiError::new(ErrorCode::MessageNotFound, "message not found").into());
+    }
+    if !m.require_acceptance {
+        return Err(ApiError::new(
+            ErrorCode::AcceptanceNotRequired,
+            "message doesn't require acceptance",
+        )
+        .into());
+    }
+    if m.accepted_on != 0 {
+        return Err(ApiError::new(
+            ErrorCode::MessageAlreadyAccepted,
+            "message is already accepted",
+        )
+        .into());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    data.db.set_message_accepted_on(&m.id, now).await.map_err(ApiError::from)?;
+    info!(
+        user = %redact(&email),
+        message_id = %m.id,
+        event = "message_accepted",
+        "message accepted",
+    );
+    audit::record(&data.db, AuditEntry::for_message(AuditEvent::MessageAccepted, &email, &m)).await;
+    Ok(Response::ok())
+}
+
+#[derive(Deserialize)]
+struct MessageAction {
+    message_id: String,
+}
+
+/// Message Id taken from the `{id}` path segment, or from the `message_id` field
+/// of the JSON body on the deprecated endpoints.
+struct MessageId(String);
+
+/// Bearer token of the request. A missing or malformed Authorization header is
+/// reported as an `ApiError`, with the challenge of the underlying extractor.
+pub struct Bearer(BearerAuth);
+
+impl Bearer {
+    pub fn token(&self) -> &str {
+        self.0.token()
+    }
+}
+
+impl FromRequest for Bearer {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let auth = BearerAuth::from_request(req, payload);
+        Box::pin(async move {
+            auth.await.map(Bearer).map_err(|e| {
+                let challenge = e.error_response();
+                let err = ApiError::new(ErrorCode::InvalidToken, "bearer token required");
+                let mut res = err.error_response();
+                if let Some(v) = challenge.headers().get(WWW_AUTHENTICATE) {
+                    res.headers_mut().insert(WWW_AUTHENTICATE, v.clone());
+                }
+                InternalError::from_response(err, res).into()
+            })
+        })
+    }
+}
+
+impl FromRequest for MessageId {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if let Some(id) = req.match_info().get("id") {
+            let id = id.to_owned();
+            return Box::pin(async move { Ok(MessageId(id)) });
+        }
+        let body = web::Json::<MessageAction>::from_request(req, payload);
+        Box::pin(async move { Ok(MessageId(body.await?.into_inner().message_id)) })
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages/{id}/claim",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Claim filed"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_claim(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let m = data
+        .db
+        .get_message(&message_id.0)
+        .await
+        .http_error(ErrorCode::MessageNotFound, "message not found")?;
+    if m.recipient != email {
+        return Err(ApiError::new(ErrorCode::MessageNotFound, "message not found").into());
+    }
+    if m.claim_veto_minutes == 0 {
+        return Err(ApiError::new(
+            ErrorCode::ClaimsNotAllowed,
+            "owner doesn't allow claims on this message",
+        )
+        .into());
+    }
+    let owner = data.db.get_user(&m.owner).await.http_internal_error("cannot get message owner")?;
+    if m.revealed || m.should_reveal(owner.last_seen).unwrap_or_default() {
+        return Err(ApiError::new(
+            ErrorCode::MessageAlreadyRevealed,
+            "message is already revealed",
+        )
+        .into());
+    }
+    if m.is_claim_pending(owner.last_seen) {
+        return Err(ApiError::new(
+            ErrorCode::ClaimPending,
+            "a claim on this message is already pending",
+        )
+        .into());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if !m.can_claim_again(now) {
+        return Err(ApiError::new(
+            ErrorCode::ClaimPending,
+            "a claim on this message was filed or vetoed recently, try again later",
+        )
+        .into());
+    }
+
+    // the owner must know about the claim before the veto window starts
+    let push_message = AppPushMessage {
+        tag: "claim".to_string(),
+        title: "Secret message claimed!".to_string(),
+        message: email.clone()
+            + " has claimed your secret message. Open Ywinby to object, otherwise it will be \
+               revealed in "
+            + &m.claim_veto_minutes.to_string()
+            + " minutes.",
+    };
+    data.web_push
+        .send_message(owner.subscription, push_message)
+        .await
+        .http_error(ErrorCode::NotificationFailed, "cannot notify owner about the claim")?;
+
+    data.db.set_message_claimed_on(&m.id, now).await.map_err(ApiError::from)?;
+    info!(user = %redact(&email), message_id = %m.id, event = "claim_filed", "claim filed");
+    audit::record(&data.db, AuditEntry::for_message(AuditEvent::ClaimFiled, &email, &m)).await;
+    let entry = AuditEntry::for_message(AuditEvent::NotificationSent, &email, &m);
+    audit::record(&data.db, entry.with_details("claim")).await;
+    Ok(Response::ok())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/messages/{id}/claim",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Claim vetoed"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_claim_veto(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let m = data
+        .db
+        .get_message(&message_id.0)
+        .await
+        .http_error(ErrorCode::MessageNotFound, "message not found")?;
+    if m.owner != email {
+        return Err(ApiError::new(ErrorCode::MessageNotFound, "message not found").into());
+    }
+    if m.revealed {
+        return Err(ApiError::new(
+            ErrorCode::MessageAlreadyRevealed,
+            "message is already revealed",
+        )
+        .into());
+    }
+    if m.claimed_on == 0 {
+        return Err(
+            ApiError::new(ErrorCode::NoPendingClaim, "there's no claim on this message").into()
+        );
     }
-    let recipient = data
+    data.db.set_message_claimed_on(&m.id, 0).await.map_err(ApiError::from)?;
+    info!(user = %redact(&email), message_id = %m.id, event = "claim_vetoed", "claim vetoed");
+    audit::record(&data.db, AuditEntry::for_message(AuditEvent::ClaimVetoed, &email, &m)).await;
+
+    if let Ok(recipient) = data.db.get_user(&m.recipient).await {
+        let push_message = AppPushMessage {
+            tag: "claim".to_string(),
+            title: "Claim rejected".to_string(),
+            message: email.clone() + " has objected to your claim on their secret message.",
+        };
+        match data.web_push.send_message(recipient.subscription, push_message).await {
+            Ok(_) => {
+                let entry = AuditEntry::for_message(AuditEvent::NotificationSent, &email, &m);
+                audit::record(&data.db, entry.with_details("claim")).await;
+            }
+            Err(e) => error!(
+                user = %redact(&recipient.id),
+                message_id = %m.id,
+                "cannot notify recipient about the vetoed claim: {}",
+                e
+            ),
+        }
+    }
+    Ok(Response::ok())
+}
+
+async fn get_message_for_contact(
+    data: &web::Data<AppState>,
+    email: &str,
+    message_id: &str,
+) -> Result<SecretMessage> {
+    let m = data
         .db
-        .get_user(m.recipient.as_str())
+        .get_message(message_id)
         .await
-        .http_not_found_error("recipient email is not registered")?;
-    if recipient.id == email {
-        return Err(ErrorForbidden("owner and recipient must be different"));
+        .http_error(ErrorCode::MessageNotFound, "message not found")?;
+    if !m.trusted_contacts.iter().any(|c| c == email) {
+        return Err(ApiError::new(ErrorCode::MessageNotFound, "message not found").into());
+    }
+    if m.revealed {
+        return Err(ApiError::new(
+            ErrorCode::MessageAlreadyRevealed,
+            "message is already revealed",
+        )
+        .into());
+    }
+    Ok(m)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages/{id}/vouch",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Vouch recorded"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_vouch(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let m = get_message_for_contact(&data, &email, &message_id.0).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let owner = data.db.get_user(&m.owner).await.map_err(ApiError::from)?;
+    if now > m.vouch_limit(owner.last_seen) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRequest,
+            "the owner must check in before vouching can extend the countdown again",
+        )
+        .into());
+    }
+    data.db.set_message_vouched_on(&m.id, now).await.map_err(ApiError::from)?;
+    info!(user = %redact(&email), message_id = %m.id, event = "owner_vouched", "owner vouched for");
+    audit::record(&data.db, AuditEntry::for_message(AuditEvent::OwnerVouched, &email, &m)).await;
+    Ok(Response::ok())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages/{id}/incapacity-confirmation",
+    tag = "messages",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Confirmation recorded"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn message_confirm_incapacity(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let m = get_message_for_contact(&data, &email, &message_id.0).await?;
+    let owner = data.db.get_user(&m.owner).await.http_internal_error("cannot get message owner")?;
+    if !m.missed_check_in(owner.last_seen) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRequest,
+            "incapacity can only be confirmed after the owner missed a check-in",
+        )
+        .into());
     }
-    if recipient.subscription.keys.auth.is_empty() {
-        return Err(ErrorForbidden("recipient hasn't subscribe to push notification"));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    data.db
+        .set_message_incapacity_confirmed_on(&m.id, &email, now)
+        .await
+        .map_err(ApiError::from)?;
+    info!(
+        user = %redact(&email),
+        message_id = %m.id,
+        event = "incapacity_confirmed",
+        "owner incapacity confirmed",
+    );
+    let entry = AuditEntry::for_message(AuditEvent::IncapacityConfirmed, &email, &m);
+    audit::record(&data.db, entry).await;
+
+    // the confirmation completing the quorum starts the owner's veto window
+    let had_quorum = m.incapacity_quorum_on(owner.last_seen).is_some();
+    let updated = data.db.get_message(&m.id).await.http_internal_error("cannot get message")?;
+    if !had_quorum && updated.incapacity_quorum_on(owner.last_seen).is_some() {
+        notify_owner_of_incapacity(&data, &email, &updated, owner).await;
     }
-    m.owner = email.to_owned();
-    data.db.put_message(m).await.map_err(|e| string_error::into_err(e.to_string()))?;
-    info!("{} created message for {})", email, recipient.id);
     Ok(Response::ok())
 }
+
+/// Tells the owner that their trusted contacts confirmed their incapacity, and
+/// how long they have to check in before the message is revealed.
+async fn notify_owner_of_incapacity(data: &AppState, email: &str, m: &SecretMessage, owner: User) {
+    let push_message = AppPushMessage {
+        tag: "incapacity".to_string(),
+        title: "Incapacity confirmed".to_string(),
+        message: "Your trusted contacts confirmed that you can't respond. Open Ywinby to object, \
+                  otherwise your secret message will be revealed in "
+            .to_string()
+            + &(m.incapacity_veto_seconds() / 60).to_string()
+            + " minutes.",
+    };
+    match data.web_push.send_message(owner.subscription, push_message).await {
+        Ok(_) => {
+            let entry = AuditEntry::for_message(AuditEvent::NotificationSent, email, m);
+            audit::record(&data.db, entry.with_details("incapacity")).await;
+        }
+        Err(e) => error!(
+            user = %redact(&m.owner),
+            message_id = %m.id,
+            "cannot notify owner about the confirmed incapacity: {}",
+            e
+        ),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuditLog {
+    chain_valid: bool,
+    entries: Vec<AuditEntry>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit-log",
+    tag = "audit",
+    responses(
+        (status = 200, body = AuditLog),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+async fn audit_log(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    let email = authorize_user(auth.token(), &data).await?;
+    let (chain_valid, entries) =
+        audit::entries_for(&data.db, &email).await.map_err(ApiError::from)?;
+    if !chain_valid {
+        error!(event = "audit_chain_broken", "audit log chain is broken");
+    }
+    Ok(web::Json(AuditLog { chain_valid, entries }))
+}
+
+async fn metrics_endpoint(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    if data.metrics_token.is_empty() {
+        return Err(ApiError::new(ErrorCode::FeatureDisabled, "this feature is not active").into());
+    }
+    if auth.token() != data.metrics_token {
+        return Err(ApiError::new(ErrorCode::Unauthorized, "correct access token required").into());
+    }
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics::gather()))
+}
+
+/// Registers the versioned API, and the original endpoints as deprecated
+/// aliases of it.
+pub fn configure(cfg: &mut ServiceConfig) {
+    let probe = |path: &str| web::resource(path).wrap(Limit::new(ratelimit::PROBES));
+    cfg.service(probe("/healthz").route(web::get().to(health::healthz)))
+        .service(probe("/metrics").route(web::get().to(metrics_endpoint)))
+        .service(probe("/readyz").route(web::get().to(health::readyz)))
+        .service(probe("/version").route(web::get().to(health::version)));
+
+    // routes with their own bucket are registered ahead of the API scope, so
+    // that they aren't counted in both
+    cfg.service(
+        web::resource("/api/v1/scheduled-task")
+            .wrap(Limit::new(ratelimit::SCHEDULED_TASK))
+            .route(web::post().to(serverless_scheduled_task)),
+    )
+    .service(
+        web::resource("/api/v1/notifications/test")
+            .wrap(Limit::new(ratelimit::NOTIFICATIONS))
+            .route(web::post().to(test_notification)),
+    );
+    cfg.service(
+        web::scope("/api/v1")
+            .wrap(Limit::new(ratelimit::API))
+            .route("/openapi.json", web::get().to(openapi::openapi_spec))
+            .route("/messages", web::get().to(message_list))
+            .route("/messages", web::post().to(message_create))
+            .route("/messages/{id}", web::get().to(message_get))
+            .route("/messages/{id}", web::delete().to(message_delete))
+            .route("/messages/{id}/accept", web::post().to(message_accept))
+            .route("/messages/{id}/claim", web::post().to(message_claim))
+            .route("/messages/{id}/claim", web::delete().to(message_claim_veto))
+            .route("/messages/{id}/vouch", web::post().to(message_vouch))
+            .route(
+                "/messages/{id}/incapacity-confirmation",
+                web::post().to(message_confirm_incapacity),
+            )
+            .route("/user", web::delete().to(account::account_delete))
+            .route("/user/export", web::get().to(account::account_export))
+            .route("/user/register", web::post().to(register_user))
+            .route("/user/check-in", web::post().to(user_pong))
+            .route("/user/subscription", web::get().to(subscription_status))
+            .route("/user/subscription", web::put().to(subscribe_user))
+            .route("/user/subscription", web::delete().to(unsubscribe_user))
+            .route("/user/preferences", web::put().to(update_preferences))
+            .route("/audit-log", web::get().to(audit_log))
+            .service(admin::scope()),
+    );
+
+    let legacy = |path: &str, bucket| {
+        web::resource(path)
+            .wrap(DefaultHeaders::new().add(("Deprecation", "true")))
+            .wrap(Limit::new(bucket))
+    };
+    cfg.service(
+        legacy("/serverless-task", ratelimit::SCHEDULED_TASK)
+            .route(web::get().to(serverless_scheduled_task)),
+    )
+    .service(legacy("/message-list", ratelimit::API).route(web::get().to(message_list)))
+    .service(
+        legacy("/message", ratelimit::API)
+            .route(web::post().to(message_create))
+            .route(web::delete().to(message_delete)),
+    )
+    .service(legacy("/user-pong", ratelimit::API).route(web::get().to(user_pong)))
+    .service(legacy("/subscribe-user", ratelimit::API).route(web::post().to(subscribe_user)))
+    .service(legacy("/unsubscribe-user", ratelimit::API).route(web::post().to(unsubscribe_user)))
+    .service(
+        legacy("/test-notification", ratelimit::NOTIFICATIONS)
+            .route(web::post().to(test_notification)),
+    );
+}
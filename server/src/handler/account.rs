@@ -0,0 +1,167 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_http::Response;
+use actix_web::{web, Responder, Result};
+use serde::Serialize;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use super::{http_error::IntoHttpError, limit_user, verify_identity, ApiError, AppState, Bearer};
+use crate::{
+    audit,
+    data_struct::{AuditEntry, AuditEvent, SecretMessage, User},
+    notifier::AppPushMessage,
+    ratelimit,
+    telemetry::redact,
+};
+
+/// Everything stored about a user.
+#[derive(Serialize, ToSchema)]
+pub struct AccountExport {
+    exported_on: u64,
+    user: User,
+    owned_messages: Vec<SecretMessage>,
+    received_messages: Vec<SecretMessage>,
+    /// Messages the user is a trusted contact for.
+    contact_messages: Vec<SecretMessage>,
+    audit_entries: Vec<AuditEntry>,
+}
+
+/// Returns the message as the user may see it, system shares are only
+/// included once revealed and notes are disclosed as in the message list.
+fn exported_message(id: &str, m: &SecretMessage, email: &str) -> SecretMessage {
+    let disclosed = m.revealed && (m.owner == email || m.recipient == email);
+    SecretMessage {
+        id: id.to_owned(),
+        system_share: if disclosed { m.system_share.clone() } else { String::new() },
+        note: if disclosed || m.owner == email { m.note.clone() } else { String::new() },
+        invite_token_hash: String::new(),
+        ..m.clone()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/export",
+    tag = "user",
+    responses(
+        (status = 200, body = AccountExport),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn account_export(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    // disabled and pending users can still get their data
+    let email = verify_identity(auth.token(), &data).await?.email;
+    limit_user(&data, ratelimit::API, &email).await?;
+    let user = data.db.get_user(&email).await.map_err(ApiError::from)?;
+    let messages = data.db.get_all_messages().await.map_err(ApiError::from)?;
+    let (_, audit_entries) = audit::entries_for(&data.db, &email).await.map_err(ApiError::from)?;
+
+    let select = |f: &dyn Fn(&SecretMessage) -> bool| -> Vec<SecretMessage> {
+        messages.iter().filter(|(_, m)| f(m)).map(|(k, m)| exported_message(k, m, &email)).collect()
+    };
+    let export = AccountExport {
+        exported_on: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        owned_messages: select(&|m| m.owner == email),
+        received_messages: select(&|m| m.recipient == email),
+        contact_messages: select(&|m| m.trusted_contacts.contains(&email)),
+        audit_entries,
+        user,
+    };
+    info!(user = %redact(&email), event = "account_exported", "account data exported");
+    Ok(web::Json(export))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user",
+    tag = "user",
+    responses(
+        (status = 200, description = "Account deleted, received messages put on hold"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn account_delete(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    // disabled and pending users can still delete their account
+    let email = verify_identity(auth.token(), &data).await?.email;
+    limit_user(&data, ratelimit::API, &email).await?;
+    let messages = data.db.get_all_messages().await.map_err(ApiError::from)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    // owned messages can't be revealed without the owner's check-ins, so they
+    // are deleted. Received messages wait for the recipient like an invite
+    // does, and contacts are just removed.
+    for (id, m) in messages.into_iter().filter(|(_, m)| m.involves(&email)) {
+        let m = SecretMessage { id, ..m };
+        if m.owner == email {
+            data.db.delete_message(&m.id).await.http_internal_error("cannot delete message")?;
+            info!(
+                user = %redact(&email),
+                message_id = %m.id,
+                event = "message_deleted",
+                "message deleted with account",
+            );
+            let entry = AuditEntry::for_message(AuditEvent::MessageDeleted, &email, &m);
+            audit::record(&data.db, entry.with_details("owner deleted account")).await;
+        } else if m.recipient == email {
+            data.db
+                .detach_recipient(&m.id, now)
+                .await
+                .http_internal_error("cannot detach recipient")?;
+            info!(
+                user = %redact(&email),
+                message_id = %m.id,
+                event = "recipient_detached",
+                "recipient deleted account",
+            );
+            let entry = AuditEntry::for_message(AuditEvent::RecipientDetached, &email, &m);
+            audit::record(&data.db, entry).await;
+            notify_owner(&data, &m).await;
+        } else {
+            data.db
+                .remove_trusted_contact(&m.id, &email)
+                .await
+                .http_internal_error("cannot remove trusted contact")?;
+        }
+    }
+
+    data.db.delete_user(&email).await.http_internal_error("cannot delete user")?;
+    info!(user = %redact(&email), event = "account_deleted", "account deleted");
+    let entry =
+        AuditEntry { owner: email.clone(), ..AuditEntry::new(AuditEvent::UserDeleted, &email) };
+    audit::record(&data.db, entry).await;
+    // without the key the user's audit entries can't be linked back to them
+    data.db.delete_audit_key(&email).await.http_internal_error("cannot delete audit key")?;
+    Ok(Response::ok())
+}
+
+/// Tells the owner that the message is on hold until its recipient comes back.
+async fn notify_owner(data: &AppState, m: &SecretMessage) {
+    let owner = match data.db.get_user(&m.owner).await {
+        Ok(owner) if !owner.subscription.endpoint.is_empty() => owner,
+        _ => return,
+    };
+    let push_message = AppPushMessage {
+        tag: "recipient_left".to_string(),
+        title: "Recipient left".to_string(),
+        message: m.recipient.clone()
+            + " has deleted their account. The secret message is on hold until they register \
+               again, you may want to delete it.",
+    };
+    match data.web_push.send_message(owner.subscription, push_message).await {
+        Ok(_) => {
+            let entry = AuditEntry::for_message(AuditEvent::NotificationSent, &m.owner, m);
+            audit::record(&data.db, entry.with_details("recipient_left")).await;
+        }
+        Err(e) => error!(
+            user = %redact(&m.owner),
+            message_id = %m.id,
+            "cannot notify owner of departed recipient: {}",
+            e
+        ),
+    }
+}
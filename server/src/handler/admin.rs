@@ -0,0 +1,502 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::Ordering::Acquire,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_http::{body::BoxBody, Response};
+use actix_web::{web, Responder, Result, Scope};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+
+use super::{
+    http_error::IntoHttpError, limit_user, sign_in, ApiError, AppState, Bearer, ErrorCode,
+    MessageId,
+};
+use crate::{
+    audit,
+    data_struct::{AuditEntry, AuditEvent, SecretMessage, Subscription, User},
+    metrics, ratelimit,
+    telemetry::redact,
+};
+
+/// Returns the caller's email if they are an admin.
+async fn authorize_admin(access_token: &str, data: &web::Data<AppState>) -> Result<String> {
+    let identity = sign_in(access_token, data, None).await?;
+    if !data.admins.includes(&identity) {
+        return Err(ApiError::new(ErrorCode::AdminRequired, "admin access required").into());
+    }
+    limit_user(data, ratelimit::API, &identity.email).await?;
+    Ok(identity.email)
+}
+
+async fn get_user(data: &web::Data<AppState>, email: &str) -> Result<User> {
+    Ok(data.db.get_user(email).await.http_error(ErrorCode::UserNotFound, "user not found")?)
+}
+
+async fn get_message(data: &web::Data<AppState>, id: &str) -> Result<SecretMessage> {
+    Ok(data.db.get_message(id).await.http_error(ErrorCode::MessageNotFound, "message not found")?)
+}
+
+/// Returns the user waiting for approval.
+async fn pending_user(data: &web::Data<AppState>, email: &str) -> Result<User> {
+    data.db
+        .get_user(email)
+        .await
+        .ok()
+        .filter(|u| u.pending_approval)
+        .ok_or_else(|| ApiError::new(ErrorCode::UserNotFound, "no pending registration").into())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PendingRegistration {
+    email: String,
+    /// Unix timestamp of the first sign-in.
+    requested_on: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/registrations",
+    tag = "admin",
+    responses(
+        (status = 200, body = [PendingRegistration]),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn registration_list(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    authorize_admin(auth.token(), &data).await?;
+    let users = data.db.get_all_users().await.map_err(ApiError::from)?;
+    let pending: Vec<_> = users
+        .into_iter()
+        .filter(|u| u.pending_approval)
+        .map(|u| PendingRegistration { email: u.id, requested_on: u.last_seen })
+        .collect();
+    Ok(web::Json(pending))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/registrations/{email}/approve",
+    tag = "admin",
+    params(("email" = String, Path, description = "Email of the pending user")),
+    responses(
+        (status = 200, description = "Registration approved"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn registration_approve(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let admin = authorize_admin(auth.token(), &data).await?;
+    let user = pending_user(&data, &path).await?;
+    data.db
+        .put_user(User { pending_approval: false, ..user })
+        .await
+        .http_internal_error("cannot approve registration")?;
+    info!(
+        user = %redact(&admin),
+        registrant = %redact(&path),
+        event = "registration_approved",
+        "registration approved",
+    );
+    let entry = AuditEntry {
+        recipient: path.to_string(),
+        ..AuditEntry::new(AuditEvent::UserApproved, &admin)
+    };
+    audit::record(&data.db, entry).await;
+    Ok(Response::ok())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/registrations/{email}",
+    tag = "admin",
+    params(("email" = String, Path, description = "Email of the pending user")),
+    responses(
+        (status = 200, description = "Registration rejected"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn registration_reject(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    let admin = authorize_admin(auth.token(), &data).await?;
+    let user = pending_user(&data, &path).await?;
+    data.db.delete_user(&user.id).await.map_err(ApiError::from)?;
+    info!(
+        user = %redact(&admin),
+        registrant = %redact(&path),
+        event = "registration_rejected",
+        "registration rejected",
+    );
+    let entry = AuditEntry {
+        recipient: path.to_string(),
+        ..AuditEntry::new(AuditEvent::UserRejected, &admin)
+    };
+    audit::record(&data.db, entry).await;
+    Ok(Response::ok())
+}
+
+/// Account state and message counts of a user, without any message content.
+#[derive(Serialize, ToSchema)]
+pub struct UserSummary {
+    pub email: String,
+    pub last_seen: u64,
+    pub subscribed: bool,
+    /// When pushes to the subscription started failing, 0 if they haven't.
+    pub subscription_gone_on: u64,
+    pub pending_approval: bool,
+    pub disabled: bool,
+    pub owned_messages: usize,
+    pub received_messages: usize,
+    /// Messages the user is a trusted contact of.
+    pub contact_messages: usize,
+}
+
+pub fn user_summaries(
+    users: Vec<User>,
+    messages: &BTreeMap<String, SecretMessage>,
+) -> Vec<UserSummary> {
+    users
+        .into_iter()
+        .map(|u| {
+            let count =
+                |f: &dyn Fn(&SecretMessage) -> bool| messages.values().filter(|m| f(m)).count();
+            UserSummary {
+                owned_messages: count(&|m| m.owner == u.id),
+                received_messages: count(&|m| m.recipient == u.id),
+                contact_messages: count(&|m| m.trusted_contacts.contains(&u.id)),
+                subscribed: !u.subscription.keys.auth.is_empty(),
+                subscription_gone_on: u.subscription_gone_on,
+                pending_approval: u.pending_approval,
+                disabled: u.disabled,
+                last_seen: u.last_seen,
+                email: u.id,
+            }
+        })
+        .collect()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/users",
+    tag = "admin",
+    responses(
+        (status = 200, body = [UserSummary]),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn user_list(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    authorize_admin(auth.token(), &data).await?;
+    let users = data.db.get_all_users().await.map_err(ApiError::from)?;
+    let messages = data.db.get_all_messages().await.map_err(ApiError::from)?;
+    Ok(web::Json(user_summaries(users, &messages)))
+}
+
+async fn set_user_disabled(
+    data: web::Data<AppState>,
+    email: &str,
+    auth: Bearer,
+    disabled: bool,
+) -> Result<Response<BoxBody>> {
+    let admin = authorize_admin(auth.token(), &data).await?;
+    if disabled && admin == email {
+        return Err(
+            ApiError::new(ErrorCode::InvalidRequest, "admins can't disable themselves").into()
+        );
+    }
+    let user = get_user(&data, email).await?;
+    let was_disabled = user.disabled;
+    data.db.put_user(User { disabled, ..user }).await.map_err(ApiError::from)?;
+    if was_disabled && !disabled {
+        // the owner couldn't check in while disabled, restart their countdowns
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let messages = data.db.get_all_messages().await.map_err(ApiError::from)?;
+        for id in messages.into_iter().filter(|(_, m)| m.owner == email).map(|(id, _)| id) {
+            data.db.set_message_resumed_on(&id, now).await.map_err(ApiError::from)?;
+        }
+    }
+    let (event, name) = if disabled {
+        (AuditEvent::UserDisabled, "user_disabled")
+    } else {
+        (AuditEvent::UserEnabled, "user_enabled")
+    };
+    info!(user = %redact(&admin), target_user = %redact(email), event = name, "user updated");
+    let entry = AuditEntry { recipient: email.to_owned(), ..AuditEntry::new(event, &admin) };
+    audit::record(&data.db, entry).await;
+    Ok(Response::ok())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/users/{email}/disable",
+    tag = "admin",
+    params(("email" = String, Path, description = "User email")),
+    responses(
+        (status = 200, description = "User disabled, their messages are paused"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn user_disable(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    set_user_disabled(data, &path, auth, true).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/users/{email}/enable",
+    tag = "admin",
+    params(("email" = String, Path, description = "User email")),
+    responses(
+        (status = 200, description = "User enabled, the countdowns of their messages restart"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn user_enable(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    set_user_disabled(data, &path, auth, false).await
+}
+
+/// Message state without its share, note or title.
+#[derive(Serialize, ToSchema)]
+pub struct MessageSummary {
+    pub id: String,
+    pub owner: String,
+    pub recipient: String,
+    pub trusted_contacts: usize,
+    pub created_ts: u64,
+    pub revealed: bool,
+    pub paused: bool,
+    pub pending_invite: bool,
+}
+
+impl From<&SecretMessage> for MessageSummary {
+    fn from(m: &SecretMessage) -> Self {
+        MessageSummary {
+            id: m.id.clone(),
+            owner: m.owner.clone(),
+            recipient: m.recipient.clone(),
+            trusted_contacts: m.trusted_contacts.len(),
+            created_ts: m.created_ts,
+            revealed: m.revealed,
+            paused: m.paused,
+            pending_invite: m.is_pending_invite(),
+        }
+    }
+}
+
+/// Summaries of the messages, or of those of one owner.
+pub fn message_summaries(
+    messages: &BTreeMap<String, SecretMessage>,
+    owner: Option<&str>,
+) -> Vec<MessageSummary> {
+    messages
+        .iter()
+        .filter(|(_, m)| owner.is_none_or(|o| o == m.owner))
+        .map(|(id, m)| MessageSummary { id: id.clone(), ..m.into() })
+        .collect()
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct MessageFilter {
+    /// Only list messages of this owner
+    owner: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/messages",
+    tag = "admin",
+    params(MessageFilter),
+    responses(
+        (status = 200, body = [MessageSummary]),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn message_list(
+    data: web::Data<AppState>,
+    filter: web::Query<MessageFilter>,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    authorize_admin(auth.token(), &data).await?;
+    let messages = data.db.get_all_messages().await.map_err(ApiError::from)?;
+    Ok(web::Json(message_summaries(&messages, filter.owner.as_deref())))
+}
+
+async fn set_message_paused(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+    paused: bool,
+) -> Result<Response<BoxBody>> {
+    let admin = authorize_admin(auth.token(), &data).await?;
+    let m = get_message(&data, &message_id.0).await?;
+    data.db.set_message_paused(&message_id.0, paused).await.map_err(ApiError::from)?;
+    let (event, name) = if paused {
+        (AuditEvent::MessagePaused, "message_paused")
+    } else {
+        (AuditEvent::MessageResumed, "message_resumed")
+    };
+    info!(user = %redact(&admin), message_id = %message_id.0, event = name, "message updated");
+    let m = SecretMessage { id: message_id.0, ..m };
+    audit::record(&data.db, AuditEntry::for_message(event, &admin, &m)).await;
+    Ok(Response::ok())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/messages/{id}/pause",
+    tag = "admin",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Message paused"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn message_pause(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    set_message_paused(data, message_id, auth, true).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/messages/{id}/resume",
+    tag = "admin",
+    params(("id" = String, Path, description = "Message Id")),
+    responses(
+        (status = 200, description = "Message resumed, its countdown restarts"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn message_resume(
+    data: web::Data<AppState>,
+    message_id: MessageId,
+    auth: Bearer,
+) -> Result<impl Responder> {
+    set_message_paused(data, message_id, auth, false).await
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PurgeResult {
+    purged: usize,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/subscriptions/purge",
+    tag = "admin",
+    responses(
+        (status = 200, body = PurgeResult, description = "Subscriptions the push service \
+                                                          reported gone were removed"),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn subscription_purge(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    let admin = authorize_admin(auth.token(), &data).await?;
+    let users = data.db.get_all_users().await.map_err(ApiError::from)?;
+    let mut purged = 0;
+    for u in users.into_iter().filter(|u| u.subscription_gone_on != 0) {
+        let user = User { subscription: Subscription::default(), subscription_gone_on: 0, ..u };
+        data.db.put_user(user.clone()).await.map_err(ApiError::from)?;
+        let entry = AuditEntry {
+            recipient: user.id,
+            ..AuditEntry::new(AuditEvent::SubscriptionPurged, &admin)
+        };
+        audit::record(&data.db, entry).await;
+        purged += 1;
+    }
+    info!(user = %redact(&admin), purged, event = "subscriptions_purged", "subscriptions purged");
+    Ok(web::Json(PurgeResult { purged }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SchedulerState {
+    period_seconds: u64,
+    /// Tasks are triggered through /api/v1/scheduled-task instead of a timer.
+    serverless: bool,
+    /// Whether a serverless task run is in progress.
+    running: bool,
+    /// Unix timestamp of the last successful run by this instance, 0 if none.
+    last_success: u64,
+    messages: usize,
+    revealed: usize,
+    paused: usize,
+    pending_invites: usize,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/scheduler",
+    tag = "admin",
+    responses(
+        (status = 200, body = SchedulerState),
+        (status = "4XX", body = ApiError),
+        (status = "5XX", body = ApiError)
+    ),
+    security(("bearer" = []))
+)]
+pub async fn scheduler_state(data: web::Data<AppState>, auth: Bearer) -> Result<impl Responder> {
+    authorize_admin(auth.token(), &data).await?;
+    let messages = data.db.get_all_messages().await.map_err(ApiError::from)?;
+    let count = |f: fn(&SecretMessage) -> bool| messages.values().filter(|m| f(m)).count();
+    Ok(web::Json(SchedulerState {
+        period_seconds: data.scheduled_task_period,
+        serverless: !data.serverless_token.is_empty(),
+        running: data.scheduled_task_running.load(Acquire),
+        last_success: metrics::SCHEDULER_LAST_SUCCESS.get() as u64,
+        messages: messages.len(),
+        revealed: count(|m| m.revealed),
+        paused: count(|m| m.paused),
+        pending_invites: count(SecretMessage::is_pending_invite),
+    }))
+}
+
+pub fn scope() -> Scope {
+    web::scope("/admin")
+        .route("/registrations", web::get().to(registration_list))
+        .route("/registrations/{email}/approve", web::post().to(registration_approve))
+        .route("/registrations/{email}", web::delete().to(registration_reject))
+        .route("/users", web::get().to(user_list))
+        .route("/users/{email}/disable", web::post().to(user_disable))
+        .route("/users/{email}/enable", web::post().to(user_enable))
+        .route("/messages", web::get().to(message_list))
+        .route("/messages/{id}/pause", web::post().to(message_pause))
+        .route("/messages/{id}/resume", web::post().to(message_resume))
+        .route("/subscriptions/purge", web::post().to(subscription_purge))
+        .route("/scheduler", web::get().to(scheduler_state))
+}
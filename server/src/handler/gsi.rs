@@ -1,6 +1,16 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
 use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::{metrics, registration::Identity};
 
 #[derive(Deserialize)]
 struct JsonWebKey {
@@ -31,53 +41,108 @@ struct Claims {
     name: String,
     iat: u64,
     exp: u64,
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
 }
 
 use isahc::prelude::*;
 
+#[instrument]
 async fn get_google_pubkeys() -> Result<String> {
     let res = isahc::get_async("https://www.googleapis.com/oauth2/v3/certs").await?.text().await?;
     Ok(res)
 }
 
-pub async fn get_email_from_token(token: &str, client_id: &str) -> Result<String> {
+/// How long a JWKS check result is reused for, so that readiness probes don't
+/// fetch Google's keys on every request.
+const JWKS_CHECK_TTL: Duration = Duration::from_secs(300);
+
+static JWKS_CHECKED: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+/// Whether Google's JWKS could be fetched, as of the last check within
+/// JWKS_CHECK_TTL.
+pub async fn jwks_available() -> bool {
+    let cached = *JWKS_CHECKED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, ok)) = cached.filter(|(t, _)| t.elapsed() < JWKS_CHECK_TTL) {
+        return ok;
+    }
+    let ok = match check_jwks().await {
+        Ok(_) => true,
+        Err(e) => {
+            tracing::warn!("google JWKS check failed: {}", e);
+            false
+        }
+    };
+    *JWKS_CHECKED.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), ok));
+    ok
+}
+
+async fn check_jwks() -> Result<()> {
     let keys = get_google_pubkeys().await?;
     let google_certs: GoogleCerts = serde_json::from_str(keys.as_str())?;
+    if google_certs.keys.is_empty() {
+        return Err(anyhow::format_err!("google JWKS has no keys"));
+    }
+    Ok(())
+}
+
+/// Counts an authentication failure under the given reason before passing the
+/// error on.
+fn failed<E: Into<anyhow::Error>>(reason: &'static str) -> impl FnOnce(E) -> anyhow::Error {
+    move |e| {
+        metrics::auth_failure(reason);
+        e.into()
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn verify_token(token: &str, client_id: &str) -> Result<Identity> {
+    let keys = get_google_pubkeys().await.map_err(failed("jwks_unavailable"))?;
+    let google_certs: GoogleCerts =
+        serde_json::from_str(keys.as_str()).map_err(failed("jwks_unavailable"))?;
 
-    let header = decode_header(token)?;
+    let header = decode_header(token).map_err(failed("malformed_token"))?;
     let jwk: JsonWebKey;
     if let Some(v) = header.kid {
         jwk = google_certs
             .keys
             .into_iter()
             .find(|x| x.kid == v)
-            .ok_or_else(|| anyhow::format_err!("cannot find matching JWK for {}", v))?;
+            .ok_or_else(|| anyhow::format_err!("cannot find matching JWK for {}", v))
+            .map_err(failed("unknown_key"))?;
     } else {
+        metrics::auth_failure("malformed_token");
         return Err(anyhow::Error::msg("invalid google JWK key format"));
     }
 
-    let key = &DecodingKey::from_rsa_components(jwk.n.as_str(), jwk.e.as_str())?;
+    let key = &DecodingKey::from_rsa_components(jwk.n.as_str(), jwk.e.as_str())
+        .map_err(failed("unknown_key"))?;
 
     let mut validation = Validation::new(Algorithm::RS256);
     validation.set_audience(&[client_id]);
 
     // expired tokens, etc are handled here
     let token_data = decode::<Claims>(token, key, &validation)
-        .map_err(|e| anyhow::format_err!("cannot decode JWT token: {:?} token: {:?}", e, token))?;
+        .map_err(|e| anyhow::format_err!("cannot decode JWT token: {:?}", e))
+        .map_err(failed("invalid_token"))?;
 
     if token_data.claims.iss.as_str() != GOOGLE_ISSUER {
+        metrics::auth_failure("wrong_issuer");
         return Err(anyhow::format_err!("wrong issuer"));
     }
 
     if token_data.claims.aud.as_str() != client_id {
+        metrics::auth_failure("wrong_audience");
         return Err(anyhow::format_err!("wrong client ID"));
     }
 
     if token_data.claims.email.is_empty() {
+        metrics::auth_failure("missing_email");
         return Err(anyhow::format_err!("cannot read email from token"));
     }
     if !token_data.claims.email_verified {
+        metrics::auth_failure("unverified_email");
         return Err(anyhow::format_err!("email is not verified"));
     }
-    Ok(token_data.claims.email)
+    Ok(Identity::new(token_data.claims.email, token_data.claims.other))
 }
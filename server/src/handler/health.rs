@@ -0,0 +1,70 @@
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use super::{gsi, AppState};
+
+#[derive(Serialize, ToSchema)]
+pub struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+}
+
+/// Whether each readiness check passed, failures are only detailed in the log.
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    ready: bool,
+    storage: bool,
+    vapid_key: bool,
+    jwks: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "Process is alive"))
+)]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok\n")
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, body = Readiness)
+    )
+)]
+pub async fn readyz(data: web::Data<AppState>) -> impl Responder {
+    let check = |name: &str, res: anyhow::Result<()>| match res {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("readiness check {} failed: {}", name, e);
+            false
+        }
+    };
+    let storage = check("storage", data.db.check_connection().await);
+    let vapid_key = check("vapid_key", data.web_push.check_key());
+    let jwks = gsi::jwks_available().await;
+    let ready = storage && vapid_key && jwks;
+    let readiness = Readiness { ready, storage, vapid_key, jwks };
+    if ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "health",
+    responses((status = 200, body = VersionInfo))
+)]
+pub async fn version() -> impl Responder {
+    web::Json(VersionInfo { name: env!("CARGO_PKG_NAME"), version: env!("CARGO_PKG_VERSION") })
+}
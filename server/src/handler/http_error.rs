@@ -1,44 +1,155 @@
+use std::fmt;
+
 use actix_http::StatusCode;
-use log::error;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use tracing::error;
+use utoipa::ToSchema;
 
-pub trait IntoHttpError<T> {
-    fn http_error(
-        self,
-        message: &str,
-        status_code: StatusCode,
-    ) -> core::result::Result<T, actix_web::Error>;
+use crate::db::DBError;
 
-    fn http_internal_error(self, message: &str) -> core::result::Result<T, actix_web::Error>
-    where
-        Self: std::marker::Sized,
-    {
-        self.http_error(message, StatusCode::INTERNAL_SERVER_ERROR)
+/// Stable error codes returned to clients, these must not be renamed.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    InvalidToken,
+    UserNotRegistered,
+    UserNotFound,
+    Unauthorized,
+    RegistrationPending,
+    AdminRequired,
+    UserDisabled,
+    FeatureDisabled,
+    TaskRunning,
+    RateLimited,
+    VerificationTooShort,
+    OwnerIsRecipient,
+    RecipientNotRegistered,
+    RecipientNotSubscribed,
+    RecipientNotAllowed,
+    InvalidTrustedContact,
+    TrustedContactNotRegistered,
+    TrustedContactNotSubscribed,
+    MessageNotFound,
+    MessageAlreadyRevealed,
+    MessageAlreadyAccepted,
+    AcceptanceNotRequired,
+    ClaimsNotAllowed,
+    ClaimPending,
+    NoPendingClaim,
+    NotificationFailed,
+    StorageError,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status_code(&self) -> StatusCode {
+        use ErrorCode::*;
+        match self {
+            InvalidRequest => StatusCode::BAD_REQUEST,
+            InvalidToken | UserNotRegistered | Unauthorized => StatusCode::UNAUTHORIZED,
+            UserNotFound
+            | RecipientNotRegistered
+            | TrustedContactNotRegistered
+            | MessageNotFound => StatusCode::NOT_FOUND,
+            RegistrationPending
+            | AdminRequired
+            | UserDisabled
+            | VerificationTooShort
+            | OwnerIsRecipient
+            | RecipientNotSubscribed
+            | RecipientNotAllowed
+            | InvalidTrustedContact
+            | TrustedContactNotSubscribed
+            | MessageAlreadyRevealed
+            | MessageAlreadyAccepted
+            | AcceptanceNotRequired
+            | ClaimsNotAllowed
+            | ClaimPending
+            | NoPendingClaim => StatusCode::FORBIDDEN,
+            TaskRunning | RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            FeatureDisabled => StatusCode::NOT_IMPLEMENTED,
+            NotificationFailed | StorageError | InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
-    fn http_unauthorized_error(self, message: &str) -> core::result::Result<T, actix_web::Error>
-    where
-        Self: std::marker::Sized,
-    {
-        self.http_error(message, StatusCode::UNAUTHORIZED)
+}
+
+/// Error returned by handlers, rendered as a JSON body with `code`, `message`
+/// and `details` fields.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError { code, message: message.into(), details: None }
+    }
+
+    pub fn with_details(self, details: impl Into<String>) -> Self {
+        ApiError { details: Some(details.into()), ..self }
     }
-    fn http_not_found_error(self, message: &str) -> core::result::Result<T, actix_web::Error>
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+/// Storage validation and lookup errors keep their message, anything else is
+/// logged and reported as a generic storage error.
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<DBError>() {
+            Some(DBError::Invalid(m)) => ApiError::new(ErrorCode::InvalidRequest, m.as_str()),
+            Some(DBError::MessageNotFound) => {
+                ApiError::new(ErrorCode::MessageNotFound, "message not found")
+            }
+            None => {
+                error!("storage error: {:?}", err);
+                ApiError::new(ErrorCode::StorageError, "storage error")
+            }
+        }
+    }
+}
+
+pub trait IntoHttpError<T> {
+    fn http_error(self, code: ErrorCode, message: &str) -> core::result::Result<T, ApiError>;
+
+    fn http_internal_error(self, message: &str) -> core::result::Result<T, ApiError>
     where
         Self: std::marker::Sized,
     {
-        self.http_error(message, StatusCode::NOT_FOUND)
+        self.http_error(ErrorCode::InternalError, message)
     }
 }
 
 impl<T, E: std::fmt::Debug> IntoHttpError<T> for core::result::Result<T, E> {
-    fn http_error(
-        self,
-        message: &str,
-        status_code: StatusCode,
-    ) -> core::result::Result<T, actix_web::Error> {
+    fn http_error(self, code: ErrorCode, message: &str) -> core::result::Result<T, ApiError> {
         match self {
             Ok(val) => Ok(val),
             Err(err) => {
-                error!("http error returned: ({}) {}. Err debug: {:?}", status_code, message, err);
-                Err(actix_web::error::InternalError::new(message.to_string(), status_code).into())
+                error!(
+                    "http error returned: ({}) {}. Err debug: {:?}",
+                    code.status_code(),
+                    message,
+                    err
+                );
+                Err(ApiError::new(code, message))
             }
         }
     }
@@ -0,0 +1,111 @@
+use actix_web::{HttpResponse, Responder};
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use super::{
+    account::AccountExport,
+    admin::{MessageSummary, PendingRegistration, PurgeResult, SchedulerState, UserSummary},
+    health::{Readiness, VersionInfo},
+    ApiError, AuditLog, ErrorCode, MessageDraft, NewMessage, RegistrationRequest,
+    SubscriptionRequest, SubscriptionStatus, TestNotificationRequest, UserPreferences,
+};
+use crate::data_struct::{
+    AuditEntry, AuditEvent, ContactConfirmation, Keys, MessageWithLastSeen, SecretMessage,
+    Subscription, User,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Ywinby API",
+        description = "Ywinby server REST API",
+        license(name = "MIT")
+    ),
+    paths(
+        super::message_list,
+        super::message_create,
+        super::message_get,
+        super::message_delete,
+        super::message_accept,
+        super::message_claim,
+        super::message_claim_veto,
+        super::message_vouch,
+        super::message_confirm_incapacity,
+        super::register_user,
+        super::user_pong,
+        super::subscription_status,
+        super::subscribe_user,
+        super::unsubscribe_user,
+        super::update_preferences,
+        super::account::account_export,
+        super::account::account_delete,
+        super::test_notification,
+        super::audit_log,
+        super::serverless_scheduled_task,
+        super::admin::registration_list,
+        super::admin::registration_approve,
+        super::admin::registration_reject,
+        super::admin::user_list,
+        super::admin::user_disable,
+        super::admin::user_enable,
+        super::admin::message_list,
+        super::admin::message_pause,
+        super::admin::message_resume,
+        super::admin::subscription_purge,
+        super::admin::scheduler_state,
+        super::health::healthz,
+        super::health::readyz,
+        super::health::version,
+    ),
+    components(schemas(
+        ApiError,
+        ErrorCode,
+        AuditLog,
+        AuditEntry,
+        AuditEvent,
+        NewMessage,
+        MessageDraft,
+        SecretMessage,
+        MessageWithLastSeen,
+        ContactConfirmation,
+        SubscriptionRequest,
+        SubscriptionStatus,
+        Subscription,
+        Keys,
+        TestNotificationRequest,
+        UserPreferences,
+        User,
+        AccountExport,
+        RegistrationRequest,
+        PendingRegistration,
+        UserSummary,
+        MessageSummary,
+        PurgeResult,
+        SchedulerState,
+        Readiness,
+        VersionInfo,
+    )),
+    modifiers(&BearerAuth)
+)]
+struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+pub async fn openapi_spec() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(ApiDoc::openapi().to_json().unwrap_or_default())
+}
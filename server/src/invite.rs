@@ -0,0 +1,115 @@
+use anyhow::{format_err, Result};
+use isahc::{AsyncReadResponseExt, Request};
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{data_struct::MessageRole, db};
+
+/// Where invite links are sent.
+enum Channel {
+    Disabled,
+    Email { transport: Box<AsyncSmtpTransport<Tokio1Executor>>, from: Mailbox },
+    Webhook { url: String },
+}
+
+/// Body posted to the invite webhook.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    owner: &'a str,
+    recipient: &'a str,
+    invite_link: &'a str,
+}
+
+/// Sends invite links to recipients who aren't registered or subscribed yet.
+pub struct Inviter {
+    channel: Channel,
+    link_base: String,
+}
+
+impl Inviter {
+    /// Invites go by email if smtp_url is set, or to the webhook if
+    /// webhook_url is, and are disabled otherwise.
+    pub fn new(
+        smtp_url: &str,
+        email_from: &str,
+        webhook_url: &str,
+        link_base: &str,
+    ) -> Result<Self> {
+        let channel = if !smtp_url.is_empty() {
+            let transport =
+                Box::new(AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_url)?.build());
+            let from = email_from
+                .parse()
+                .map_err(|e| format_err!("invalid invite sender {}: {}", email_from, e))?;
+            Channel::Email { transport, from }
+        } else if !webhook_url.is_empty() {
+            Channel::Webhook { url: webhook_url.to_owned() }
+        } else {
+            Channel::Disabled
+        };
+        Ok(Self { channel, link_base: link_base.trim_end_matches('/').to_owned() })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self.channel, Channel::Disabled)
+    }
+
+    /// Link to the web client that registers the recipient with the token.
+    fn link(&self, token: &str) -> String {
+        format!("{}/?invite={}", self.link_base, token)
+    }
+
+    pub async fn send(&self, owner: &str, recipient: &str, token: &str) -> Result<()> {
+        let invite_link = self.link(token);
+        match &self.channel {
+            Channel::Disabled => Err(format_err!("no invite channel is configured")),
+            Channel::Email { transport, from } => {
+                let email = Message::builder()
+                    .from(from.clone())
+                    .to(recipient.parse()?)
+                    .subject(format!("{} left you a secret message on Ywinby", owner))
+                    .body(format!(
+                        "{} has registered a secret message for you on Ywinby.\n\nSign in with \
+                         this email address and enable notifications to receive it:\n{}\n",
+                        owner, invite_link
+                    ))?;
+                transport.send(email).await?;
+                Ok(())
+            }
+            Channel::Webhook { url } => {
+                let payload =
+                    WebhookPayload { event: "invite", owner, recipient, invite_link: &invite_link };
+                let req = Request::post(url)
+                    .header("content-type", "application/json")
+                    .body(serde_json::to_vec(&payload)?)?;
+                let mut res = isahc::send_async(req).await?;
+                if !res.status().is_success() {
+                    let body = res.text().await.unwrap_or_default();
+                    return Err(format_err!("webhook returned {}: {}", res.status(), body));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn new_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Tokens are only stored hashed.
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether the token belongs to a pending message addressed to the email.
+pub async fn is_invited(dbo: &db::DB, email: &str, token: &str) -> bool {
+    let hash = token_hash(token);
+    match dbo.get_messages_by_role(MessageRole::Recipient, email).await {
+        Ok(messages) => messages.values().any(|m| m.invite_token_hash == hash),
+        Err(_) => false,
+    }
+}
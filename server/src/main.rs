@@ -1,106 +1,53 @@
 #![deny(elided_lifetimes_in_paths)]
+mod audit;
+mod cli;
+mod config;
 mod data_struct;
 mod db;
 mod handler;
+mod invite;
+mod metrics;
 mod notifier;
+mod ratelimit;
+mod registration;
+mod security;
+mod telemetry;
+mod tls;
 
-use std::{fs::create_dir_all, sync::Arc};
+use std::{fs::create_dir_all, path::Path, sync::Arc};
 
-use actix_cors::Cors;
 use actix_files as fs;
 use actix_web_httpauth::extractors::bearer;
 use anyhow::Result;
-use clap::Parser;
-use db::StorageType;
-use handler::AppState;
-use log::{error, info};
+use config::Args;
+use handler::{ApiError, AppState, ErrorCode};
 use serde::Serialize;
 use simple_on_shutdown::on_shutdown;
-use sloggers::{
-    terminal::{Destination, TerminalLoggerBuilder},
-    types::Severity,
-    Build,
-};
-
-#[derive(Parser)]
-#[command(
-    author("https://github.com/mmta"),
-    version,
-    about = "Ywinby server",
-    long_about = "Ywinby server\n\nA system that keeps one share of the 2-of-3 Shamir's secret \
-                  sharing system,\nand release it to the recipient if the owner fails respond \
-                  after a certain time."
-)]
-struct Args {
-    /// Scheduled task period
-    #[arg(short('t'), long, env, value_name = "seconds", default_value_t = 3600)]
-    scheduled_task_period: u64,
-    #[arg(value_enum)]
-    /// Storage type to use
-    #[arg(short('s'), long, env, default_value = "json")]
-    storage: StorageType,
-    /// GCP Project ID if using Firestore
-    #[arg(short('j'), long, env, default_value = "", required_if_eq("storage", "firestore"))]
-    project_id: String,
-    /// Base64 VAPID private key for web push notification.
-    #[arg(
-        short('k'),
-        long,
-        env,
-        value_name = "strings",
-        required_unless_present("generate"),
-        default_value = ""
-    )]
-    push_privkey: String,
-    /// Public key of the above to be used by web clients, will be written to
-    /// runtime-config.json.
-    #[arg(
-        short('p'),
-        long,
-        env,
-        value_name = "strings",
-        required_unless_present("generate"),
-        default_value = ""
-    )]
-    push_pubkey: String,
-    /// The URL that web clients use to contact this server, will be written to
-    /// runtime-config.json.
-    #[arg(short('u'), long, env, value_name = "url", default_value = "http://localhost:8080")]
-    base_api_path: String,
-    /// Google oAuth2 Client ID of the app that users will be signing in to.
-    #[arg(
-        short('c'),
-        long,
-        env,
-        value_name = "client_id",
-        default_value = "806452214643-l366imhlc0c64coebiik6t3otfjatis3.apps.googleusercontent.com"
-    )]
-    client_id: String,
-    /// Block new user registration
-    #[arg(short('b'), env)]
-    block_registration: bool,
-    /// Activate serverless mode, and authenticate request for scheduled task
-    /// using this token
-    #[arg(short('e'), long("serverless_token"), env, default_value = "")]
-    serverless_token: String,
-    /// Generate new VAPID private and public keys
-    #[arg(short('g'), long("generate"))]
-    generate: bool,
-    /// Increase logging verbosity
-    #[arg(short('v'), long, action = clap::ArgAction::Count)]
-    verbosity: u8,
-}
+use tracing::{error, info};
 
-fn update_client_config(api_path: String, pubkey: String) -> Result<()> {
+fn update_client_config(dir: &Path, api_path: String, pubkey: String) -> Result<()> {
     let cfg = WebClientRuntimeConfig { api_url: api_path, push_pubkey_base64: pubkey };
-    let dir = std::env::current_exe()?.parent().unwrap().join("static");
-    create_dir_all(&dir)?;
+    create_dir_all(dir)?;
     let file = dir.join("runtime-config.json");
     let cfg_str = serde_json::to_string_pretty(&cfg)?;
     std::fs::write(file, cfg_str)?;
     Ok(())
 }
 
+/// Removes a socket file left behind by a previous run, so that binding it
+/// doesn't fail. Sockets something still listens on are kept.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket && UnixStream::connect(path).is_err() {
+        info!("removing stale socket {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
 fn generate_keys() -> Result<()> {
     let k = vapid::Key::generate().map_err(|err| {
         error!("exiting, cannot generate key: {:?}", err);
@@ -108,7 +55,8 @@ fn generate_keys() -> Result<()> {
     })?;
     println!(
         "These can be used for push_privkey (-k) and push_pubkey (-p) parameters:\n- privateKey: \
-         {}\n- publicKey: {}
+         {}\n- publicKey: {}\nWhen replacing a key, keep the old privateKey in \
+         previous_push_privkeys.
 ",
         k.to_private_raw(),
         k.to_public_raw()
@@ -124,104 +72,157 @@ struct WebClientRuntimeConfig {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let args = config::load()?;
 
     if args.generate {
         return generate_keys();
     }
 
-    let log_severity = match args.verbosity {
-        0 => Severity::Info,
-        1 => Severity::Debug,
-        _ => Severity::Trace,
-    };
-    let logger = TerminalLoggerBuilder::new()
-        .level(log_severity)
-        .destination(Destination::Stderr)
-        .build()
+    let _telemetry = telemetry::init(args.verbosity, args.log_format, &args.otlp_endpoint)
         .map_err(|err| {
-            error!("exiting, cannot configure logger: {:?}", err);
+            eprintln!("exiting, cannot configure tracing: {:?}", err);
             err
         })?;
-    let _guard = sloggers::set_stdlog_logger(logger).map_err(|err| {
-        error!("exiting, cannot set standard logger: {:?}", err);
-        err
-    })?;
 
-    update_client_config(args.base_api_path, args.push_pubkey).map_err(|err| {
+    if let Some(command) = &args.command {
+        return cli::run(&args, command).await;
+    }
+
+    update_client_config(&args.static_root, args.base_api_path.clone(), args.push_pubkey.clone())
+        .map_err(|err| {
         error!("exiting, cannot update client config: {:?}", err);
         err
     })?;
 
-    let storage_id =
-        if args.storage == StorageType::Firestore { args.project_id } else { "db".to_string() };
-
-    on_shutdown!(|| {
-        _guard.cancel_reset();
-        info!("server is shutting down")
-    });
+    on_shutdown!(|| info!("server is shutting down"));
 
     if !args.serverless_token.is_empty() {
         info!("starting in serverless mode, not activating scheduler");
     } else {
+        let args = args.clone();
         tokio::spawn(async move {
             notifier::start_scheduler(
                 args.storage,
-                &storage_id,
+                &args.storage_id(),
                 args.scheduled_task_period,
-                args.push_privkey,
+                args.push_privkey.clone(),
+                args.previous_push_privkeys.clone(),
+                args.retention(),
             )
             .await
         });
     }
 
-    use actix_web::{App, HttpServer};
+    use actix_web::{web, App, HttpServer};
 
-    HttpServer::new(|| {
+    let rate_limiter = web::Data::new(
+        ratelimit::RateLimiter::new(
+            &args.rate_limits,
+            &args.rate_limit_redis_url,
+            args.trust_proxy_headers,
+        )
+        .await
+        .map_err(|err| {
+            error!("exiting, cannot initialize rate limiter: {:?}", err);
+            err
+        })?,
+    );
+    let state =
+        web::Data::new(create_app_state(&args, rate_limiter.clone()).await.map_err(|err| {
+            error!("exiting, cannot initialize storage and web push client: {:?}", err);
+            err
+        })?);
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let resolver = Arc::new(tls::CertResolver::new(cert, key).map_err(|err| {
+                error!("exiting, cannot load TLS certificate: {:?}", err);
+                err
+            })?);
+            let watched = resolver.clone();
+            tokio::spawn(async move {
+                if let Err(err) = watched.watch().await {
+                    error!("TLS certificate is no longer reloaded: {:?}", err);
+                }
+            });
+            Some(tls::server_config(resolver))
+        }
+        _ => None,
+    };
+    let static_root = args.static_root.clone();
+    let allowed_origins = args.allowed_origins();
+    let content_security_policy = args.content_security_policy();
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_header()
-                    .allowed_methods(["GET", "POST", "DELETE", "CONNECT", "OPTIONS"])
-                    .disable_vary_header(),
-            )
-            .data_factory(|| async {
-                let state = data_factory_creator().await.unwrap();
-                Ok::<_, ()>(state)
-            })
+            .wrap(security::cors(&allowed_origins))
+            .wrap(security::headers(&content_security_policy))
+            .wrap(telemetry::RequestSpan)
+            .app_data(state.clone())
+            .app_data(rate_limiter.clone())
             .app_data(bearer::Config::default().realm("Registered-users only").scope("Ywinby"))
-            .service(handler::message_list)
-            .service(handler::message_create)
-            .service(handler::message_delete)
-            .service(handler::user_pong)
-            .service(handler::subscribe_user)
-            .service(handler::unsubscribe_user)
-            .service(handler::test_notification)
-            .service(handler::serverless_scheduled_task)
-            .service(fs::Files::new("/", "./static").index_file("index.html"))
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                ApiError::new(ErrorCode::InvalidRequest, "invalid request body")
+                    .with_details(err.to_string())
+                    .into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                ApiError::new(ErrorCode::InvalidRequest, "invalid query string")
+                    .with_details(err.to_string())
+                    .into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                ApiError::new(ErrorCode::InvalidRequest, "invalid path")
+                    .with_details(err.to_string())
+                    .into()
+            }))
+            .configure(handler::configure)
+            .service(fs::Files::new("/", &static_root).index_file("index.html"))
     })
-    .workers(1)
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await?;
+    .workers(args.workers);
+
+    for addr in args.listen.iter() {
+        info!("listening on {}", addr);
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix(config::UNIX_SOCKET_PREFIX) {
+            remove_stale_socket(Path::new(path))?;
+            server = server.bind_uds(path)?;
+            continue;
+        }
+        server = match &tls_config {
+            Some(tls) => server.bind_rustls_0_22(addr.as_str(), tls.clone())?,
+            None => server.bind(addr.as_str())?,
+        };
+    }
+    server.run().await?;
     Ok(())
 }
 
-async fn data_factory_creator() -> Result<AppState> {
-    let args = Args::parse();
-    let s_id =
-        if args.storage == StorageType::Firestore { args.project_id } else { "db".to_string() };
-    let web_pusher = Arc::new(notifier::WebPusher::new(args.push_privkey)?);
-    let sdb = db::DBBuilder::new(args.storage, &s_id).await?;
+async fn create_app_state(
+    args: &Args,
+    rate_limiter: actix_web::web::Data<ratelimit::RateLimiter>,
+) -> Result<AppState> {
+    let web_pusher = Arc::new(notifier::WebPusher::new(
+        args.push_privkey.clone(),
+        args.previous_push_privkeys.clone(),
+    )?);
+    let sdb = db::DBBuilder::new(args.storage, &args.storage_id()).await?;
 
     Ok(handler::AppState {
         db: sdb,
         web_push: web_pusher.to_owned(),
-        block_registration: args.block_registration,
+        registration: args.registration_policy(),
+        admins: args.admin_role(),
+        inviter: invite::Inviter::new(
+            &args.invite_smtp_url,
+            &args.invite_email_from,
+            &args.invite_webhook_url,
+            &args.base_api_path,
+        )?,
         scheduled_task_period: args.scheduled_task_period,
         oauth_client_id: args.client_id.to_owned(),
         serverless_token: args.serverless_token.to_owned(),
+        metrics_token: args.metrics_token.to_owned(),
         scheduled_task_running: std::sync::atomic::AtomicBool::new(false),
+        retention: args.retention(),
+        rate_limiter,
     })
 }
@@ -0,0 +1,96 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_gauge, register_gauge_vec, register_histogram, register_histogram_vec,
+    register_int_counter_vec, Encoder, Gauge, GaugeVec, Histogram, HistogramTimer, HistogramVec,
+    IntCounterVec, TextEncoder,
+};
+
+pub static MESSAGES: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "ywinby_messages",
+        "Number of messages by state, as of the last scheduler run",
+        &["state"]
+    )
+    .unwrap()
+});
+
+pub static NOTIFICATIONS_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "ywinby_notifications_sent_total",
+        "Push notifications sent, by tag",
+        &["tag"]
+    )
+    .unwrap()
+});
+
+pub static NOTIFICATIONS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "ywinby_notifications_failed_total",
+        "Push notifications that failed to send, by tag",
+        &["tag"]
+    )
+    .unwrap()
+});
+
+pub static SCHEDULER_RUN_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "ywinby_scheduler_run_duration_seconds",
+        "Duration of scheduled task executions"
+    )
+    .unwrap()
+});
+
+pub static SCHEDULER_LAST_SUCCESS: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "ywinby_scheduler_last_success_timestamp_seconds",
+        "Unix time of the last successful scheduled task execution"
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "ywinby_rate_limited_total",
+        "Requests rejected by the rate limiter, by bucket",
+        &["bucket"]
+    )
+    .unwrap()
+});
+
+pub static AUTH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "ywinby_auth_failures_total",
+        "Rejected authentication attempts, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static STORAGE_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "ywinby_storage_latency_seconds",
+        "Latency of storage operations",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+pub fn auth_failure(reason: &str) {
+    AUTH_FAILURES.with_label_values(&[reason]).inc();
+}
+
+/// Starts timing a storage operation, the duration is recorded when the
+/// returned timer is dropped.
+pub fn storage_timer(operation: &str) -> HistogramTimer {
+    STORAGE_LATENCY.with_label_values(&[operation]).start_timer()
+}
+
+/// Renders all registered metrics in the Prometheus text format.
+pub fn gather() -> String {
+    let mut buf = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buf) {
+        tracing::error!("cannot encode metrics: {}", e);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
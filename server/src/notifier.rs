@@ -1,14 +1,21 @@
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
-use log::{error, info};
+use anyhow::{format_err, Result};
+use base64::Engine;
 use serde::Serialize;
 use tokio::{task, time};
+use tracing::{debug, error, info, instrument};
 use web_push::*;
 
 use crate::{
-    data_struct::{SecretMessage, Subscription, User},
+    audit::{self, SYSTEM_ACTOR},
+    data_struct::{AuditEntry, AuditEvent, RetentionPolicy, SecretMessage, Subscription, User},
     db::{self, StorageType},
+    metrics,
+    telemetry::redact,
 };
 
 const PUSH_SUBJECT_CLAIM: &str = "https://github.com/mmta/ywinby";
@@ -18,11 +25,14 @@ pub async fn start_scheduler(
     storage_id: &str,
     every_seconds: u64,
     webpush_privkey_base64: String,
+    previous_privkeys_base64: Vec<String>,
+    retention: RetentionPolicy,
 ) -> Result<()> {
-    let web_pusher = WebPusher::new(webpush_privkey_base64).map_err(|e| {
-        error!("cannot start scheduler, failed to initialize web push client: {:?}", e);
-        e
-    })?;
+    let web_pusher =
+        WebPusher::new(webpush_privkey_base64, previous_privkeys_base64).map_err(|e| {
+            error!("cannot start scheduler, failed to initialize web push client: {:?}", e);
+            e
+        })?;
     let sdb = db::DBBuilder::new(storage_type, storage_id).await?;
 
     info!("scheduler will execute task every {} seconds", every_seconds);
@@ -31,7 +41,7 @@ pub async fn start_scheduler(
         let mut interval = time::interval(Duration::from_secs(every_seconds));
         loop {
             interval.tick().await;
-            execute_tasks(&sdb, &web_pusher)
+            execute_tasks(&sdb, &web_pusher, &retention)
                 .await
                 .map_err(|e| error!("error executing task: {}", e))
                 .unwrap_or_default();
@@ -40,51 +50,252 @@ pub async fn start_scheduler(
     .await?
 }
 
-pub async fn execute_tasks(dbo: &db::DB, pusher: &WebPusher) -> Result<()> {
+#[instrument(skip_all)]
+pub async fn execute_tasks(
+    dbo: &db::DB,
+    pusher: &WebPusher,
+    retention: &RetentionPolicy,
+) -> Result<()> {
     info!("start executing scheduled task");
+    let timer = metrics::SCHEDULER_RUN_DURATION.start_timer();
 
     let messages = dbo.get_all_messages().await?;
+    let revealed = messages.values().filter(|m| m.revealed).count();
+    metrics::MESSAGES.with_label_values(&["revealed"]).set(revealed as f64);
+    metrics::MESSAGES.with_label_values(&["pending"]).set((messages.len() - revealed) as f64);
 
     let mut notifications: HashSet<Notification> = HashSet::new();
 
-    for (k, v) in messages {
-        let o = dbo.get_user(&v.owner).await;
-        if o.is_err() {
-            error!("cannot get owner for {}, skip processing", k);
-            continue;
-        }
-        let r = dbo.get_user(&v.recipient).await;
-        if r.is_err() {
-            error!("cannot get recipient for {}, skip processing", k);
-            continue;
-        }
-        if let Some(v) = get_notification(o.unwrap(), r.unwrap(), k.clone(), v.clone()) {
-            notifications.insert(Notification {
-                app_message: v.0,
-                subscription: v.1.subscription,
-                email: v.1.id,
-                message_id: k,
-            });
-        }
+    for (k, v) in messages.iter() {
+        collect_notifications(dbo, retention, k, v, &mut notifications).await;
     }
     for n in notifications {
+        let tag = n.app_message.tag.clone();
         if let Err(e) = pusher.send_message(n.subscription.clone(), n.app_message).await {
-            error!("cannot push notification to {} about message {}: {}", n.email, n.message_id, e);
-            error!("subscription endpoint: {:?}", n.subscription.endpoint);
+            error!(
+                user = %redact(&n.email),
+                message_id = %n.message_id,
+                "cannot push {} notification: {}",
+                tag,
+                e
+            );
+            if is_endpoint_gone(&e) {
+                mark_subscription_gone(dbo, &n.email).await;
+            }
         } else {
-            info!("push message sent for message Id: {}", n.message_id);
+            info!(
+                user = %redact(&n.email),
+                message_id = %n.message_id,
+                event = "notification_sent",
+                "{} notification sent",
+                tag
+            );
+            if let Some(m) = messages.get(&n.message_id) {
+                let entry = AuditEntry::for_message(AuditEvent::NotificationSent, SYSTEM_ACTOR, m);
+                audit::record(dbo, entry.with_details(&tag)).await;
+            }
             if let Err(e) = dbo.update_message_notified_on(n.message_id.as_str(), &n.email).await {
-                error!("cannot set message last notification timestamp {}: {}", n.message_id, e);
+                error!(message_id = %n.message_id, "cannot set last notification timestamp: {}", e);
             }
             if let Err(e) = dbo.set_message_revealed_if_needed(&n.message_id).await {
-                error!("cannot set message revealed flag {}: {}", n.message_id, e);
+                error!(message_id = %n.message_id, "cannot set revealed flag: {}", e);
             }
         }
     }
+    request_resubscriptions(dbo, pusher).await;
+    timer.observe_duration();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    metrics::SCHEDULER_LAST_SUCCESS.set(now as f64);
     info!("done executing scheduled task");
     Ok(())
 }
 
+/// Applies the retention policy to the message, otherwise queues the
+/// notifications it is due for.
+#[instrument(skip_all, fields(message_id = %k))]
+async fn collect_notifications(
+    dbo: &db::DB,
+    retention: &RetentionPolicy,
+    k: &str,
+    v: &SecretMessage,
+    notifications: &mut HashSet<Notification>,
+) {
+    let k = k.to_owned();
+    let o = dbo.get_user(&v.owner).await;
+    let owner_last_seen = o.as_ref().map(|u| u.last_seen).unwrap_or_default();
+    if apply_retention(dbo, retention, &k, v, owner_last_seen).await {
+        return;
+    }
+    if v.is_pending_invite() {
+        debug!(message_id = %k, "recipient hasn't accepted the invite yet, skip processing");
+        return;
+    }
+    if v.paused {
+        debug!(message_id = %k, "message is paused, skip processing");
+        return;
+    }
+    if o.is_err() {
+        error!(message_id = %k, "cannot get owner, skip processing");
+        return;
+    }
+    let r = dbo.get_user(&v.recipient).await;
+    if r.is_err() {
+        error!(message_id = %k, "cannot get recipient, skip processing");
+        return;
+    }
+    let owner = o.unwrap();
+    if owner.disabled {
+        debug!(message_id = %k, "owner is disabled, skip processing");
+        return;
+    }
+    if let Ok(true) = v.should_notify_contacts(owner.last_seen) {
+        for c in v.trusted_contacts.iter() {
+            match dbo.get_user(c).await {
+                Ok(contact) => {
+                    let (app_message, contact) = get_contact_notification(&owner, contact);
+                    notifications.insert(Notification {
+                        app_message,
+                        subscription: contact.subscription,
+                        email: contact.id,
+                        message_id: k.clone(),
+                    });
+                }
+                Err(_) => error!(
+                    message_id = %k,
+                    user = %redact(c),
+                    "cannot get trusted contact, skip notifying"
+                ),
+            }
+        }
+    }
+    if let Some(v) = get_notification(owner, r.unwrap(), k.clone(), v.clone()) {
+        notifications.insert(Notification {
+            app_message: v.0,
+            subscription: v.1.subscription,
+            email: v.1.id,
+            message_id: k,
+        });
+    }
+}
+
+/// Deletes expired messages and the system shares of revealed ones past their
+/// retention period. Returns whether the message needs no further processing.
+async fn apply_retention(
+    dbo: &db::DB,
+    retention: &RetentionPolicy,
+    k: &str,
+    v: &SecretMessage,
+    owner_last_seen: u64,
+) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if v.revealed && v.revealed_on == 0 {
+        // revealed before reveal times were recorded, start counting now
+        if let Err(e) = dbo.set_message_revealed_on(k, now).await {
+            error!(message_id = %k, "cannot set reveal time: {}", e);
+        }
+        return false;
+    }
+    if let Ok(true) = v.should_purge_share(retention) {
+        match dbo.purge_system_share(k, now).await {
+            Ok(_) => {
+                info!(
+                    message_id = %k,
+                    event = "system_share_purged",
+                    "system share purged by retention policy",
+                );
+                let m = SecretMessage { id: k.to_owned(), ..v.clone() };
+                let entry =
+                    AuditEntry::for_message(AuditEvent::SystemSharePurged, SYSTEM_ACTOR, &m);
+                audit::record(dbo, entry.with_details("retention policy")).await;
+            }
+            Err(e) => error!(message_id = %k, "cannot purge system share: {}", e),
+        }
+        return true;
+    }
+    if let Ok(true) = v.should_expire(retention, owner_last_seen) {
+        match dbo.delete_message(k).await {
+            Ok(_) => {
+                info!(
+                    message_id = %k,
+                    event = "message_deleted",
+                    "message expired by retention policy",
+                );
+                let m = SecretMessage { id: k.to_owned(), ..v.clone() };
+                let entry = AuditEntry::for_message(AuditEvent::MessageDeleted, SYSTEM_ACTOR, &m);
+                audit::record(dbo, entry.with_details("retention policy")).await;
+            }
+            Err(e) => error!(message_id = %k, "cannot delete expired message: {}", e),
+        }
+        return true;
+    }
+    false
+}
+
+/// Whether the push service reported that the subscription no longer exists.
+pub fn is_endpoint_gone(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<WebPushError>(),
+        Some(WebPushError::EndpointNotValid | WebPushError::EndpointNotFound)
+    )
+}
+
+/// Flags the user's subscription so that admins can purge it.
+async fn mark_subscription_gone(dbo: &db::DB, email: &str) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    match dbo.get_user(email).await {
+        Ok(user) if user.subscription_gone_on == 0 => {
+            match dbo.put_user(User { subscription_gone_on: now, ..user }).await {
+                Ok(_) => info!(
+                    user = %redact(email),
+                    event = "subscription_gone",
+                    "subscription is gone",
+                ),
+                Err(e) => error!(user = %redact(email), "cannot flag gone subscription: {}", e),
+            }
+        }
+        Ok(_) => (),
+        Err(e) => error!(user = %redact(email), "cannot flag gone subscription: {}", e),
+    }
+}
+
+/// Asks users whose subscription was created with a previous push key to open
+/// the app, which subscribes them again with the current one. Each user is
+/// asked once.
+async fn request_resubscriptions(dbo: &db::DB, pusher: &WebPusher) {
+    let users = match dbo.get_all_users().await {
+        Ok(users) => users,
+        Err(e) => {
+            error!("cannot get users to request resubscription: {}", e);
+            return;
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    for user in users.into_iter().filter(|u| {
+        !u.subscription.keys.auth.is_empty()
+            && u.resubscribe_requested_on == 0
+            && !pusher.is_current(&u.subscription)
+    }) {
+        let msg = AppPushMessage {
+            tag: "resubscribe".to_owned(),
+            title: "Notifications need renewing".to_owned(),
+            message: "Please open Ywinby so that you keep receiving its notifications.".to_owned(),
+        };
+        if let Err(e) = pusher.send_message(user.subscription.clone(), msg).await {
+            error!(user = %redact(&user.id), "cannot push resubscribe notification: {}", e);
+            continue;
+        }
+        let email = user.id.clone();
+        match dbo.put_user(User { resubscribe_requested_on: now, ..user }).await {
+            Ok(_) => info!(
+                user = %redact(&email),
+                event = "resubscribe_requested",
+                "asked user to subscribe again",
+            ),
+            Err(e) => error!(user = %redact(&email), "cannot record resubscribe request: {}", e),
+        }
+    }
+}
+
 #[derive(Eq, Hash, PartialEq)]
 struct Notification {
     email: String,
@@ -99,11 +310,11 @@ fn get_notification(
     k: String,
     v: SecretMessage,
 ) -> Option<(AppPushMessage, User)> {
-    info!("processing message Id: {}", k);
+    debug!(message_id = %k, "processing message");
 
     // owner first, so they will receive the configured max number of notifications
     if let Ok(true) = v.should_notify_owner(owner.last_seen) {
-        info!("notifying owner {}", owner.id);
+        info!(message_id = %k, user = %redact(&owner.id), "notifying owner");
         let msg = AppPushMessage {
             tag: "owner".to_owned(),
             title: "Owner verification".to_owned(),
@@ -115,21 +326,33 @@ fn get_notification(
     // notify recipient on the next execute_task cycle, of which should_verify_owner
     // will have return false
     if let Ok(true) = v.should_notify_recipient(owner.last_seen) {
-        info!("notifying recipient {}", recipient.id);
+        info!(message_id = %k, user = %redact(&recipient.id), "notifying recipient");
 
         let msg = AppPushMessage {
             tag: "recipient".to_owned(),
             title: "Secret message unlocked!".to_owned(),
             message: "You can now reveal the message from ".to_owned()
                 + owner.id.as_str()
-                + ". Please delete the message after that to stop this alert.",
+                + ". This alert will repeat daily until you do.",
         };
         return Some((msg, recipient));
     }
-    info!("notification not sent for Id: {}", k);
+    debug!(message_id = %k, "no notification due");
     None
 }
 
+fn get_contact_notification(owner: &User, contact: User) -> (AppPushMessage, User) {
+    info!(user = %redact(&contact.id), "notifying trusted contact");
+    let msg = AppPushMessage {
+        tag: "contact".to_owned(),
+        title: "Owner missed a check-in".to_owned(),
+        message: owner.id.clone()
+            + " hasn't responded to Ywinby. Please vouch for them if you know they're fine, or \
+               confirm their incapacity otherwise.",
+    };
+    (msg, contact)
+}
+
 #[derive(Serialize, PartialEq, Eq, Hash, Default)]
 pub struct AppPushMessage {
     pub tag: String,
@@ -137,22 +360,116 @@ pub struct AppPushMessage {
     pub message: String,
 }
 
-pub struct WebPusher {
+/// Whether the push service refused the VAPID signature, which happens when the
+/// subscription was created with another key.
+fn is_key_mismatch(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<WebPushError>() {
+        Some(WebPushError::Unauthorized) => true,
+        // statuses without a variant of their own are reported as the bare code
+        Some(WebPushError::Other(status)) => status.trim().parse::<u16>() == Ok(403),
+        _ => false,
+    }
+}
+
+/// Converts a base64 public key to the URL-safe, unpadded form that
+/// subscriptions are tracked with.
+pub fn normalize_pubkey(pubkey: &str) -> String {
+    pubkey.trim_end_matches('=').replace('+', "-").replace('/', "_")
+}
+
+struct VapidKey {
     privkey_base64: String,
+    pubkey_base64: String,
+}
+
+impl VapidKey {
+    fn new(privkey_base64: String) -> Result<Self> {
+        let pubkey_base64 = derive_pubkey(&privkey_base64)?;
+        Ok(Self { privkey_base64, pubkey_base64 })
+    }
+}
+
+/// Returns the URL-safe, unpadded public key of the private key.
+pub fn derive_pubkey(privkey_base64: &str) -> Result<String> {
+    let builder =
+        VapidSignatureBuilder::from_base64_no_sub(privkey_base64, web_push::URL_SAFE_NO_PAD)?;
+    Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(builder.get_public_key()))
+}
+
+pub struct WebPusher {
+    /// The current key, followed by the previous ones.
+    keys: Vec<VapidKey>,
     client: WebPushClient,
 }
 
 impl WebPusher {
-    pub fn new(privkey_base64: String) -> Result<Self> {
+    pub fn new(privkey_base64: String, previous_privkeys_base64: Vec<String>) -> Result<Self> {
         let client = WebPushClient::new()?;
-        Ok(Self { privkey_base64, client })
+        let keys = std::iter::once(privkey_base64)
+            .chain(previous_privkeys_base64)
+            .map(VapidKey::new)
+            .collect::<Result<_>>()?;
+        Ok(Self { keys, client })
+    }
+    pub fn check_key(&self) -> Result<()> {
+        for key in self.keys.iter() {
+            VapidSignatureBuilder::from_base64_no_sub(
+                key.privkey_base64.as_str(),
+                web_push::URL_SAFE_NO_PAD,
+            )?;
+        }
+        Ok(())
+    }
+    pub fn is_known_key(&self, pubkey: &str) -> bool {
+        self.keys.iter().any(|k| k.pubkey_base64 == pubkey)
     }
+    /// Whether the subscription was created with the current key. Untracked
+    /// ones are, as long as no key has been rotated.
+    pub fn is_current(&self, sub: &Subscription) -> bool {
+        sub.vapid_pubkey == self.keys[0].pubkey_base64
+            || (sub.vapid_pubkey.is_empty() && self.keys.len() == 1)
+    }
+    #[instrument(skip_all, fields(tag = %message.tag))]
     pub async fn send_message(&self, sub: Subscription, message: AppPushMessage) -> Result<()> {
+        let tag = message.tag.clone();
+        let res = self.push(sub, message).await;
+        let counter =
+            if res.is_ok() { &metrics::NOTIFICATIONS_SENT } else { &metrics::NOTIFICATIONS_FAILED };
+        counter.with_label_values(&[&tag]).inc();
+        res
+    }
+    async fn push(&self, sub: Subscription, message: AppPushMessage) -> Result<()> {
+        // untracked subscriptions may have been created with any of the keys
+        let keys: Vec<&VapidKey> = self
+            .keys
+            .iter()
+            .filter(|k| sub.vapid_pubkey.is_empty() || k.pubkey_base64 == sub.vapid_pubkey)
+            .collect();
+        if keys.is_empty() {
+            return Err(format_err!("push key of the subscription is no longer configured"));
+        }
+        let json = serde_json::to_string(&message)?;
         let subscription_info = SubscriptionInfo::new(sub.endpoint, sub.keys.p256dh, sub.keys.auth);
+        let mut res = Ok(());
+        for key in keys {
+            res = self.push_with_key(key, &subscription_info, json.as_bytes()).await;
+            match &res {
+                Err(e) if is_key_mismatch(e) => debug!("push key refused, trying the next one"),
+                _ => break,
+            }
+        }
+        res
+    }
+    async fn push_with_key(
+        &self,
+        key: &VapidKey,
+        subscription_info: &SubscriptionInfo,
+        content: &[u8],
+    ) -> Result<()> {
         let mut sig_builder = VapidSignatureBuilder::from_base64(
-            self.privkey_base64.as_str(),
+            key.privkey_base64.as_str(),
             web_push::URL_SAFE_NO_PAD,
-            &subscription_info,
+            subscription_info,
         )
         .map_err(|e| {
             error!("cannot decode signature: {}", e);
@@ -164,10 +481,7 @@ impl WebPusher {
             e
         })?;
 
-        let json = serde_json::to_string(&message)?;
-
-        let content = json.as_bytes();
-        let mut builder = WebPushMessageBuilder::new(&subscription_info)?;
+        let mut builder = WebPushMessageBuilder::new(subscription_info)?;
         builder.set_payload(ContentEncoding::Aes128Gcm, content);
         builder.set_vapid_signature(signature);
         builder.set_ttl(1000);
@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::RETRY_AFTER,
+    web, HttpResponse, ResponseError,
+};
+use anyhow::{format_err, Result};
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+    handler::{ApiError, ErrorCode},
+    metrics,
+};
+
+/// Every API route.
+pub const API: &str = "api";
+/// Scheduled task trigger, guarded by the serverless token.
+pub const SCHEDULED_TASK: &str = "scheduled_task";
+/// Test notifications sent to other users.
+pub const NOTIFICATIONS: &str = "notifications";
+/// Test notifications from one user to another, counted per pair.
+pub const NOTIFICATION_PAIR: &str = "notification_pair";
+/// Health, readiness and metrics endpoints.
+pub const PROBES: &str = "probes";
+/// Invites sent to unregistered recipients, counted per owner.
+pub const INVITES: &str = "invites";
+
+const DEFAULT_LIMITS: [(&str, Rule); 6] = [
+    (API, Rule { count: 300, seconds: 60 }),
+    (SCHEDULED_TASK, Rule { count: 10, seconds: 60 }),
+    (NOTIFICATIONS, Rule { count: 5, seconds: 600 }),
+    (NOTIFICATION_PAIR, Rule { count: 1, seconds: 3600 }),
+    (PROBES, Rule { count: 120, seconds: 60 }),
+    (INVITES, Rule { count: 10, seconds: 86400 }),
+];
+
+/// The memory store keeps at most this many counters.
+const MAX_MEMORY_ENTRIES: usize = 10_000;
+
+/// Allows `count` requests every `seconds`, a count of 0 disables the limit.
+#[derive(Clone, Copy, Debug)]
+struct Rule {
+    count: u64,
+    seconds: u64,
+}
+
+/// Parses `bucket=count/seconds`.
+fn parse_rule(s: &str) -> Result<(String, Rule)> {
+    let invalid = || format_err!("invalid rate limit {}, expected bucket=count/seconds", s);
+    let (bucket, rule) = s.split_once('=').ok_or_else(invalid)?;
+    let (count, seconds) = rule.split_once('/').ok_or_else(invalid)?;
+    let rule = Rule {
+        count: count.trim().parse().map_err(|_| invalid())?,
+        seconds: seconds.trim().parse().map_err(|_| invalid())?,
+    };
+    let bucket = bucket.trim();
+    if rule.seconds == 0 {
+        return Err(invalid());
+    }
+    if !DEFAULT_LIMITS.iter().any(|(b, _)| *b == bucket) {
+        let known: Vec<_> = DEFAULT_LIMITS.iter().map(|(b, _)| *b).collect();
+        return Err(format_err!("unknown rate limit bucket {}, use one of {:?}", bucket, known));
+    }
+    Ok((bucket.to_owned(), rule))
+}
+
+pub fn validate_rules(rules: &[String]) -> Result<()> {
+    rules.iter().try_for_each(|r| parse_rule(r).map(|_| ()))
+}
+
+/// Window expiry and request count per key, in two generations: once the
+/// current one is full it replaces the previous one, dropping the counters that
+/// weren't hit since. This bounds memory without scanning the counters.
+#[derive(Default)]
+struct Counters {
+    current: HashMap<String, (u64, u64)>,
+    previous: HashMap<String, (u64, u64)>,
+}
+
+impl Counters {
+    fn hit(&mut self, key: &str, expires: u64) -> u64 {
+        if !self.current.contains_key(key) {
+            if self.current.len() >= MAX_MEMORY_ENTRIES / 2 {
+                self.previous = std::mem::take(&mut self.current);
+            }
+            let counter = self.previous.remove(key).unwrap_or((expires, 0));
+            self.current.insert(key.to_owned(), counter);
+        }
+        let counter = self.current.get_mut(key).expect("counter was just inserted");
+        if counter.0 != expires {
+            *counter = (expires, 0);
+        }
+        counter.1 += 1;
+        counter.1
+    }
+
+    fn unhit(&mut self, key: &str, expires: u64) {
+        if let Some(counter) = self.current.get_mut(key).filter(|c| c.0 == expires) {
+            counter.1 = counter.1.saturating_sub(1);
+        }
+    }
+}
+
+enum Store {
+    Memory(Mutex<Counters>),
+    Redis(ConnectionManager),
+}
+
+/// Fixed window request counters, kept in process or in Redis when several
+/// instances share the limits.
+pub struct RateLimiter {
+    rules: HashMap<String, Rule>,
+    store: Store,
+    trust_proxy_headers: bool,
+}
+
+impl RateLimiter {
+    pub async fn new(rules: &[String], redis_url: &str, trust_proxy_headers: bool) -> Result<Self> {
+        let mut limits: HashMap<String, Rule> =
+            DEFAULT_LIMITS.iter().map(|(b, r)| (b.to_string(), *r)).collect();
+        for r in rules {
+            let (bucket, rule) = parse_rule(r)?;
+            limits.insert(bucket, rule);
+        }
+        let store = if redis_url.is_empty() {
+            Store::Memory(Mutex::new(Counters::default()))
+        } else {
+            let client = redis::Client::open(redis_url)?;
+            Store::Redis(ConnectionManager::new(client).await?)
+        };
+        Ok(Self { rules: limits, store, trust_proxy_headers })
+    }
+
+    /// Counts the request against its client address, and returns the seconds
+    /// to wait if it's over the bucket's limit. Users are counted once their
+    /// token is verified, see `check_user`.
+    async fn check(&self, bucket: &str, req: &ServiceRequest) -> Option<u64> {
+        let ip = if self.trust_proxy_headers {
+            req.connection_info().realip_remote_addr().map(str::to_owned)
+        } else {
+            req.peer_addr().map(|a| a.ip().to_string())
+        };
+        self.check_key(bucket, &format!("ip:{}", ip?)).await
+    }
+
+    /// Counts a request from the signed-in user, and returns the seconds to
+    /// wait if it's over the bucket's limit.
+    pub async fn check_user(&self, bucket: &str, email: &str) -> Option<u64> {
+        let digest = hex::encode(Sha256::digest(email.as_bytes()));
+        self.check_key(bucket, &format!("user:{}", &digest[..32])).await
+    }
+
+    /// Counts a hit on the key under the bucket's limit, and returns the
+    /// seconds to wait if it's over.
+    pub async fn check_key(&self, bucket: &str, key: &str) -> Option<u64> {
+        let rule = self.rules.get(bucket).filter(|r| r.count > 0)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let expires = (now / rule.seconds + 1) * rule.seconds;
+        match self.hit(&format!("{}:{}", bucket, key), expires).await {
+            Ok(count) if count > rule.count => Some(expires - now),
+            Ok(_) => None,
+            Err(e) => {
+                error!("cannot update rate limit counter, allowing request: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Takes back a hit counted by `check_key`, for requests that turned out
+    /// not to count, such as failed sends.
+    pub async fn refund_key(&self, bucket: &str, key: &str) {
+        let Some(rule) = self.rules.get(bucket).filter(|r| r.count > 0) else {
+            return;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let expires = (now / rule.seconds + 1) * rule.seconds;
+        if let Err(e) = self.unhit(&format!("{}:{}", bucket, key), expires).await {
+            error!("cannot update rate limit counter: {}", e);
+        }
+    }
+
+    async fn unhit(&self, key: &str, expires: u64) -> Result<()> {
+        match &self.store {
+            Store::Memory(counters) => {
+                let mut counters = counters.lock().map_err(|e| format_err!("{}", e))?;
+                counters.unhit(key, expires);
+                Ok(())
+            }
+            Store::Redis(conn) => {
+                let key = format!("ywinby:ratelimit:{}:{}", key, expires);
+                redis::pipe()
+                    .atomic()
+                    .decr(&key, 1)
+                    .ignore()
+                    .expire_at(&key, expires as i64)
+                    .ignore()
+                    .query_async::<()>(&mut conn.clone())
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn hit(&self, key: &str, expires: u64) -> Result<u64> {
+        match &self.store {
+            Store::Memory(counters) => {
+                let mut counters = counters.lock().map_err(|e| format_err!("{}", e))?;
+                Ok(counters.hit(key, expires))
+            }
+            Store::Redis(conn) => {
+                let key = format!("ywinby:ratelimit:{}:{}", key, expires);
+                let (count,): (u64,) = redis::pipe()
+                    .atomic()
+                    .incr(&key, 1)
+                    .expire_at(&key, expires as i64)
+                    .ignore()
+                    .query_async(&mut conn.clone())
+                    .await?;
+                Ok(count)
+            }
+        }
+    }
+}
+
+pub fn too_many_requests(retry_after: u64) -> HttpResponse {
+    let mut res = ApiError::new(ErrorCode::RateLimited, "too many requests").error_response();
+    res.headers_mut().insert(RETRY_AFTER, retry_after.into());
+    res
+}
+
+/// Middleware applying the named bucket's limit, requests pass through if no
+/// `RateLimiter` is registered.
+pub struct Limit {
+    bucket: &'static str,
+}
+
+impl Limit {
+    pub fn new(bucket: &'static str) -> Self {
+        Self { bucket }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Limit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = LimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LimitMiddleware { service: Rc::new(service), bucket: self.bucket }))
+    }
+}
+
+pub struct LimitMiddleware<S> {
+    service: Rc<S>,
+    bucket: &'static str,
+}
+
+type LimitFuture<B> =
+    Pin<Box<dyn Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>>>;
+
+impl<S, B> Service<ServiceRequest> for LimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LimitFuture<B>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let bucket = self.bucket;
+        Box::pin(async move {
+            let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+            if let Some(limiter) = limiter {
+                if let Some(retry_after) = limiter.check(bucket, &req).await {
+                    metrics::RATE_LIMITED.with_label_values(&[bucket]).inc();
+                    let res = too_many_requests(retry_after);
+                    return Ok(req.into_response(res).map_into_right_body());
+                }
+            }
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        let (bucket, rule) = parse_rule("api=300/60").unwrap();
+        assert_eq!((bucket.as_str(), rule.count, rule.seconds), ("api", 300, 60));
+        let (bucket, rule) = parse_rule(" probes = 0 / 10 ").unwrap();
+        assert_eq!((bucket.as_str(), rule.count, rule.seconds), ("probes", 0, 10));
+        let (bucket, rule) = parse_rule("invites=3/86400").unwrap();
+        assert_eq!((bucket.as_str(), rule.count, rule.seconds), ("invites", 3, 86400));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for rule in ["api", "api=300", "api=x/60", "api=300/0", "api=-1/60", "=1/60", "web=1/60"] {
+            assert!(parse_rule(rule).is_err(), "{} should be rejected", rule);
+        }
+    }
+
+    #[test]
+    fn validates_every_rule() {
+        assert!(validate_rules(&[]).is_ok());
+        assert!(validate_rules(&["api=1/1".into(), "notifications=2/600".into()]).is_ok());
+        assert!(validate_rules(&["api=1/1".into(), "api=1".into()]).is_err());
+    }
+
+    #[test]
+    fn counters_reset_with_the_window() {
+        let mut counters = Counters::default();
+        assert_eq!(counters.hit("a", 100), 1);
+        assert_eq!(counters.hit("a", 100), 2);
+        counters.unhit("a", 100);
+        assert_eq!(counters.hit("a", 100), 2);
+        assert_eq!(counters.hit("a", 160), 1);
+        assert_eq!(counters.hit("b", 160), 1);
+    }
+
+    #[test]
+    fn counters_are_bounded() {
+        let mut counters = Counters::default();
+        for i in 0..MAX_MEMORY_ENTRIES * 2 {
+            counters.hit(&i.to_string(), 100);
+        }
+        assert!(counters.current.len() + counters.previous.len() <= MAX_MEMORY_ENTRIES);
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Verified user of a sign-in token.
+pub struct Identity {
+    pub email: String,
+    claims: HashMap<String, Value>,
+}
+
+impl Identity {
+    pub fn new(email: String, claims: HashMap<String, Value>) -> Self {
+        Self { email, claims }
+    }
+
+    /// Whether the token has a claim with the value, or a list claim that
+    /// contains it.
+    pub fn has_claim(&self, name: &str, value: &str) -> bool {
+        match self.claims.get(name) {
+            Some(Value::String(s)) => s == value,
+            Some(Value::Bool(b)) => b.to_string() == value,
+            Some(Value::Array(a)) => a.iter().any(|v| v.as_str() == Some(value)),
+            _ => false,
+        }
+    }
+}
+
+/// Decides who can create an account on first sign-in.
+#[derive(Clone, Default, Debug)]
+pub struct RegistrationPolicy {
+    /// Nobody new can register.
+    pub block: bool,
+    pub allowed_domains: Vec<String>,
+    pub allowed_emails: Vec<String>,
+    pub invite_codes: Vec<String>,
+    /// New users, other than allowed_emails and admins, wait for an admin to
+    /// approve them.
+    pub require_approval: bool,
+    /// A recipient's message invite admits them like an invite code does.
+    pub admit_invited_recipients: bool,
+}
+
+/// Users with access to the admin API, listed by email or holding a token
+/// claim.
+#[derive(Clone, Default, Debug)]
+pub struct AdminRole {
+    pub emails: Vec<String>,
+    /// Claim name and value that only admins' tokens carry.
+    pub claim: Option<(String, String)>,
+}
+
+impl AdminRole {
+    pub fn includes(&self, identity: &Identity) -> bool {
+        contains_ignore_case(&self.emails, &identity.email)
+            || self.claim.as_ref().is_some_and(|(name, value)| identity.has_claim(name, value))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Approved,
+    Pending,
+    Rejected,
+}
+
+fn contains_ignore_case(list: &[String], value: &str) -> bool {
+    list.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+impl RegistrationPolicy {
+    /// Whether registration is limited to some domains, emails or invite codes.
+    fn is_restricted(&self) -> bool {
+        !self.allowed_domains.is_empty()
+            || !self.allowed_emails.is_empty()
+            || !self.invite_codes.is_empty()
+    }
+
+    pub fn is_invite_code(&self, code: &str) -> bool {
+        self.invite_codes.iter().any(|c| c == code)
+    }
+
+    /// Decides on a new user, `invited` is set if they presented one of the
+    /// invite codes, or a message invite addressed to them when
+    /// admit_invited_recipients is set. Admins are always
+    /// approved unless registration is blocked.
+    pub fn admit(&self, email: &str, invited: bool, admin: bool) -> Admission {
+        if self.block {
+            return Admission::Rejected;
+        }
+        if admin || contains_ignore_case(&self.allowed_emails, email) {
+            return Admission::Approved;
+        }
+        let domain = email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+        let admitted =
+            !self.is_restricted() || contains_ignore_case(&self.allowed_domains, domain) || invited;
+        match (admitted, self.require_approval) {
+            (false, _) => Admission::Rejected,
+            (true, true) => Admission::Pending,
+            (true, false) => Admission::Approved,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Admission::*;
+
+    fn restricted() -> RegistrationPolicy {
+        RegistrationPolicy {
+            allowed_domains: vec!["x.com".into()],
+            allowed_emails: vec!["Friend@y.com".into()],
+            invite_codes: vec!["code".into()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn open_policy_admits_anyone() {
+        let policy = RegistrationPolicy::default();
+        assert_eq!(policy.admit("a@any.com", false, false), Approved);
+        let approval = RegistrationPolicy { require_approval: true, ..policy };
+        assert_eq!(approval.admit("a@any.com", false, false), Pending);
+        assert_eq!(approval.admit("a@any.com", false, true), Approved);
+    }
+
+    #[test]
+    fn restricted_policy_admits_listed_users_and_invites() {
+        let policy = restricted();
+        assert_eq!(policy.admit("a@X.com", false, false), Approved);
+        assert_eq!(policy.admit("friend@y.com", false, false), Approved);
+        assert_eq!(policy.admit("a@y.com", true, false), Approved);
+        assert_eq!(policy.admit("a@y.com", false, false), Rejected);
+        assert_eq!(policy.admit("a@y.com", false, true), Approved);
+        // the domain must match whole
+        assert_eq!(policy.admit("a@evilx.com", false, false), Rejected);
+        assert_eq!(policy.admit("x.com", false, false), Rejected);
+    }
+
+    #[test]
+    fn approval_applies_to_domains_and_invites_only() {
+        let policy = RegistrationPolicy { require_approval: true, ..restricted() };
+        assert_eq!(policy.admit("a@x.com", false, false), Pending);
+        assert_eq!(policy.admit("a@y.com", true, false), Pending);
+        assert_eq!(policy.admit("friend@y.com", false, false), Approved);
+        assert_eq!(policy.admit("a@y.com", false, false), Rejected);
+    }
+
+    #[test]
+    fn blocked_registration_rejects_everyone() {
+        let policy = RegistrationPolicy { block: true, ..restricted() };
+        assert_eq!(policy.admit("friend@y.com", true, true), Rejected);
+    }
+}
@@ -0,0 +1,46 @@
+use actix_cors::Cors;
+use actix_web::middleware::DefaultHeaders;
+
+/// Allows requests from any origin when present in the allowlist.
+pub const ANY_ORIGIN: &str = "*";
+
+/// Returns the scheme, host and port part of a URL.
+pub fn origin(url: &str) -> &str {
+    let start = url.find("://").map(|i| i + 3).unwrap_or(0);
+    match url[start..].find('/') {
+        Some(i) => &url[..start + i],
+        None => url,
+    }
+}
+
+/// Policy for the bundled web client: its own scripts plus Google sign-in,
+/// talking only to itself, the API server and Google.
+pub fn default_content_security_policy(api_url: &str) -> String {
+    format!(
+        "default-src 'self'; script-src 'self' https://accounts.google.com/gsi/client; \
+         style-src 'self' 'unsafe-inline' https://accounts.google.com/gsi/style; \
+         frame-src https://accounts.google.com/gsi/; connect-src 'self' {} \
+         https://accounts.google.com/gsi/; img-src 'self' data: https://*.googleusercontent.com; \
+         font-src 'self' data:; worker-src 'self'; manifest-src 'self'; object-src 'none'; \
+         base-uri 'self'; frame-ancestors 'none'",
+        origin(api_url)
+    )
+}
+
+pub fn cors(allowed_origins: &[String]) -> Cors {
+    let cors = Cors::default()
+        .allow_any_header()
+        .allowed_methods(["GET", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS"]);
+    if allowed_origins.iter().any(|o| o == ANY_ORIGIN) {
+        return cors.allow_any_origin();
+    }
+    allowed_origins.iter().fold(cors, |cors, o| cors.allowed_origin(o))
+}
+
+pub fn headers(content_security_policy: &str) -> DefaultHeaders {
+    DefaultHeaders::new()
+        .add(("Strict-Transport-Security", "max-age=31536000"))
+        .add(("Content-Security-Policy", content_security_policy))
+        .add(("X-Content-Type-Options", "nosniff"))
+        .add(("Referrer-Policy", "no-referrer"))
+}
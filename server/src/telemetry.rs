@@ -0,0 +1,194 @@
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+};
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::{debug, error, info_span, level_filters::LevelFilter, Instrument};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+const SERVICE_NAME: &str = "ywinby";
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, PartialEq, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Masks the local part of an email address so that logs can still be
+/// correlated by domain without identifying the user.
+pub fn redact(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => local.chars().take(1).collect::<String>() + "***@" + domain,
+        None => "***".to_owned(),
+    }
+}
+
+/// Flushes pending spans to the OTLP collector when dropped.
+pub struct Guard {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                error!("cannot flush pending spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global tracing subscriber, writing either human readable or
+/// JSON lines to stderr. Spans are exported over OTLP/HTTP when
+/// `otlp_endpoint` is set, e.g. `http://localhost:4318`. Records from crates
+/// still using `log` are forwarded to the subscriber.
+pub fn init(verbosity: u8, format: LogFormat, otlp_endpoint: &str) -> Result<Guard> {
+    let level = match verbosity {
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let text = (format == LogFormat::Text).then(|| fmt::layer().with_writer(std::io::stderr));
+    let json = (format == LogFormat::Json).then(|| {
+        fmt::layer().json().flatten_event(true).with_span_list(false).with_writer(std::io::stderr)
+    });
+
+    let provider = if otlp_endpoint.is_empty() {
+        None
+    } else {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(otlp_endpoint.trim_end_matches('/').to_owned() + "/v1/traces")
+            .build()?;
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+                .build(),
+        )
+    };
+    let otel = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer(SERVICE_NAME)));
+
+    tracing_subscriber::registry().with(level).with(text).with(json).with(otel).try_init()?;
+    Ok(Guard { provider })
+}
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Takes the caller's request id if it looks like one, so that requests can be
+/// followed through a proxy, and makes up one otherwise.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(&REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| {
+            !v.is_empty()
+                && v.len() <= 64
+                && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// Middleware running each request in a root span carrying its request id,
+/// which is returned in the X-Request-Id header.
+pub struct RequestSpan;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestSpan
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestSpanMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestSpanMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestSpanMiddleware<S> {
+    service: Rc<S>,
+}
+
+type RequestSpanFuture<B> =
+    Pin<Box<dyn Future<Output = Result<ServiceResponse<B>, actix_web::Error>>>>;
+
+impl<S, B> Service<ServiceRequest> for RequestSpanMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = RequestSpanFuture<B>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let id = request_id(&req);
+        // the route pattern rather than the path, which may hold ids and emails
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+        let span = info_span!(
+            "request",
+            request_id = %id,
+            method = %req.method(),
+            route = %route,
+            status = tracing::field::Empty,
+        );
+        Box::pin(
+            async move {
+                let started = Instant::now();
+                let mut res = service.call(req).await?;
+                let status = res.status().as_u16();
+                tracing::Span::current().record("status", status);
+                debug!(status, elapsed_ms = started.elapsed().as_millis() as u64, "request served");
+                if let Ok(value) = HeaderValue::from_str(&id) {
+                    res.headers_mut().insert(REQUEST_ID, value);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_the_local_part() {
+        assert_eq!(redact("alice@example.com"), "a***@example.com");
+        assert_eq!(redact("a@b.c"), "a***@b.c");
+        assert_eq!(redact("@example.com"), "***@example.com");
+    }
+
+    #[test]
+    fn redacts_everything_without_a_domain() {
+        assert_eq!(redact("alice"), "***");
+        assert_eq!(redact(""), "***");
+    }
+
+    #[test]
+    fn keeps_multibyte_first_character_whole() {
+        assert_eq!(redact("émile@example.com"), "é***@example.com");
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{format_err, Context, Result};
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+use tracing::{error, info};
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_CHECK_SECONDS: u64 = 30;
+
+/// Serves the most recently loaded certificate, so that it can be replaced
+/// without restarting the server.
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|k| k.clone())
+    }
+}
+
+impl CertResolver {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let current = RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?));
+        Ok(Self { cert_path: cert_path.to_owned(), key_path: key_path.to_owned(), current })
+    }
+
+    /// Replaces the served certificate, keeping the current one if the files
+    /// can't be loaded.
+    pub fn reload(&self) {
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = Arc::new(key);
+                    info!("TLS certificate reloaded from {}", self.cert_path.display());
+                }
+            }
+            Err(e) => error!("cannot reload TLS certificate, keeping the current one: {:?}", e),
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let cert = std::fs::metadata(&self.cert_path).and_then(|m| m.modified()).ok()?;
+        let key = std::fs::metadata(&self.key_path).and_then(|m| m.modified()).ok()?;
+        Some(cert.max(key))
+    }
+
+    /// Reloads the certificate on SIGHUP, or when the certificate or key file
+    /// is modified. Only file changes are watched where there are no signals.
+    pub async fn watch(self: Arc<Self>) -> Result<()> {
+        #[cfg(unix)]
+        let mut hangups = signal(SignalKind::hangup())?;
+        let mut interval = time::interval(Duration::from_secs(RELOAD_CHECK_SECONDS));
+        let mut last_modified = self.modified();
+        loop {
+            #[cfg(unix)]
+            let hangup = hangups.recv();
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+            tokio::select! {
+                _ = hangup => {
+                    info!("SIGHUP received, reloading TLS certificate");
+                    self.reload();
+                    last_modified = self.modified();
+                }
+                _ = interval.tick() => {
+                    let modified = self.modified();
+                    if modified.is_some() && modified != last_modified {
+                        self.reload();
+                        last_modified = modified;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let mut cert_file = BufReader::new(
+        File::open(cert_path)
+            .with_context(|| format!("cannot open TLS certificate {}", cert_path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_file)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid TLS certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(format_err!("no certificate found in {}", cert_path.display()));
+    }
+
+    let mut key_file = BufReader::new(
+        File::open(key_path)
+            .with_context(|| format!("cannot open TLS key {}", key_path.display()))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_file)
+        .with_context(|| format!("invalid TLS key {}", key_path.display()))?
+        .ok_or_else(|| format_err!("no private key found in {}", key_path.display()))?;
+    let signing_key = any_supported_type(&key)
+        .with_context(|| format!("unsupported TLS key {}", key_path.display()))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+pub fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    ServerConfig::builder().with_no_client_auth().with_cert_resolver(resolver)
+}